@@ -10,3 +10,35 @@ pub fn cpuid(eax: u32) -> (u32, u32, u32, u32) {
     }
     (a, b, c, d)
 }
+
+/// Query for the highest supported extended function.
+const EXTENDED_FUNCTIONS: u32 = 0x8000_0000;
+
+/// Query for the extended processor features.
+const EXTENDED_FEATURES: u32 = 0x8000_0001;
+
+/// Bit in EDX of the extended features signalling support for the no-execute bit in page tables.
+const EXTENDED_FEATURES_EDX_NX: u32 = 1 << 20;
+
+/// Bit in EDX of the extended features signalling support for 1 GiB pages.
+const EXTENDED_FEATURES_EDX_PDPE1GB: u32 = 1 << 26;
+
+/// Return the EDX register of the extended processor features, if that query is supported.
+fn extended_features_edx() -> Option<u32> {
+    let (max_function, _, _, _) = cpuid(EXTENDED_FUNCTIONS);
+    if max_function >= EXTENDED_FEATURES {
+        Some(cpuid(EXTENDED_FEATURES).3)
+    } else {
+        None
+    }
+}
+
+/// Return whether the processor supports marking pages as not executable.
+pub fn has_no_execute() -> bool {
+    extended_features_edx().map_or(false, |edx| edx & EXTENDED_FEATURES_EDX_NX != 0)
+}
+
+/// Return whether the processor supports mapping 1 GiB pages.
+pub fn has_1gb_pages() -> bool {
+    extended_features_edx().map_or(false, |edx| edx & EXTENDED_FEATURES_EDX_PDPE1GB != 0)
+}
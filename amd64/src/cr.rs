@@ -0,0 +1,30 @@
+//! Access to the control registers.
+
+use super::PhysAddr;
+
+/// Bits of CR3 holding the physical address of the PML4.
+const CR3_ADDR_MASK: usize = 0x000F_FFFF_FFFF_F000;
+
+/// Return the physical address of the currently active PML4.
+#[inline(always)]
+pub unsafe fn read_cr3() -> PhysAddr {
+    let value: usize;
+    asm!("mov $0, cr3" : "=r"(value) : : : "intel", "volatile");
+    PhysAddr(value & CR3_ADDR_MASK)
+}
+
+/// Load a new PML4, which also flushes all non-global TLB entries.
+#[inline(always)]
+pub unsafe fn write_cr3(pml4: PhysAddr) {
+    asm!("mov cr3, $0" : : "r"(pml4.0 & CR3_ADDR_MASK) : "memory" : "intel", "volatile");
+}
+
+/// CR0 bit that makes read-only pages read-only in supervisor mode as well.
+const CR0_WRITE_PROTECT: usize = 1 << 16;
+
+/// Prevent the kernel from writing to read-only pages.
+pub unsafe fn enable_write_protect() {
+    let value: usize;
+    asm!("mov $0, cr0" : "=r"(value) : : : "intel", "volatile");
+    asm!("mov cr0, $0" : : "r"(value | CR0_WRITE_PROTECT) : "memory" : "intel", "volatile");
+}
@@ -0,0 +1,105 @@
+use core::ops;
+
+use crate::segments::Selector;
+
+/// Load a GDT for the current CPU and reload all segment registers,
+/// using the given selectors for the code and the data segments.
+pub unsafe fn load_gdt(gdt: &Gdt, code: Selector, data: Selector) {
+    let gdtr = Gdtr {
+        limit: core::mem::size_of::<Gdt>() as u16 - 1,
+        offset: gdt as *const Gdt as u64,
+    };
+    asm!("lgdt [$0]" : : "r"(&gdtr) : "memory" : "intel", "volatile");
+    // CS can only be reloaded by a far jump, call or return
+    asm!("push $0
+          lea rax, [rip + 1f]
+          push rax
+          retfq
+          1:
+          mov ds, $1
+          mov es, $1
+          mov fs, $1
+          mov gs, $1
+          mov ss, $1"
+        : : "r"(code.0 as u64), "r"(data.0) : "rax", "memory" : "intel", "volatile");
+}
+
+/// GDT Register value
+#[repr(C,packed)]
+pub struct Gdtr {
+    limit: u16,
+    offset: u64,
+}
+
+/// Number of entries in a `Gdt`.
+pub const GDT_ENTRY_COUNT: usize = 8;
+
+/// Global descriptor table
+#[repr(C,packed)]
+pub struct Gdt {
+    entries: [GdtEntry; GDT_ENTRY_COUNT]
+}
+
+impl Gdt {
+    /// Create a GDT consisting of null descriptors only.
+    pub const fn new() -> Gdt {
+        Gdt {
+            entries: [GdtEntry::NULL; GDT_ENTRY_COUNT]
+        }
+    }
+
+    /// The selector referring to the entry with the given index in ring 0.
+    pub fn selector(index: usize) -> Selector {
+        assert!(index < GDT_ENTRY_COUNT);
+        Selector((index * core::mem::size_of::<GdtEntry>()) as u16)
+    }
+}
+
+impl ops::Index<usize> for Gdt {
+    type Output = GdtEntry;
+
+    fn index(&self, idx: usize) -> &GdtEntry {
+        &self.entries[idx]
+    }
+}
+
+impl ops::IndexMut<usize> for Gdt {
+    fn index_mut(&mut self, idx: usize) -> &mut GdtEntry {
+        &mut self.entries[idx]
+    }
+}
+
+/// A code or data segment descriptor. In long mode, base and limit are ignored,
+/// only the type and the privilege level matter.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(C,packed)]
+pub struct GdtEntry(u64);
+
+// sanity check that everything adds up in terms of size
+assert_eq_size!(gdt_entry_size; GdtEntry, u64);
+
+impl GdtEntry {
+    /// The descriptor that must occupy the first entry of the table.
+    pub const NULL: GdtEntry = GdtEntry(0);
+    /// 64 bit code segment for ring 0: present (47), code (43, 44), long mode (53).
+    pub const KERNEL_CODE: GdtEntry = GdtEntry(0x0020_9800_0000_0000);
+    /// Data segment for ring 0: present (47), data (44), writable (41).
+    pub const KERNEL_DATA: GdtEntry = GdtEntry(0x0000_9200_0000_0000);
+
+    /// The raw value of the descriptor.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_selectors() {
+        assert_eq!(Gdt::selector(0), Selector::NULL);
+        assert_eq!(Gdt::selector(2), Selector(16));
+        assert_eq!(core::mem::size_of::<Gdt>(), GDT_ENTRY_COUNT * 8);
+    }
+}
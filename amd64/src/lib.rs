@@ -14,12 +14,14 @@ pub mod segments;
 pub mod interrupts;
 pub mod util;
 pub mod idt;
+pub mod gdt;
 pub mod pic;
 pub mod apic;
 pub mod ioapic;
 pub mod msr;
 pub mod io;
 pub mod cpuid;
+pub mod cr;
 pub mod pit;
 pub mod cmos;
 pub mod rtc;
@@ -1,5 +1,25 @@
 pub const APIC_BASE: Msr = Msr(0x1B);
 
+/// Extended feature enable register.
+pub const EFER: Msr = Msr(0xC000_0080);
+
+/// Bit in `EFER` enabling the no-execute bit in page table entries.
+const EFER_NXE: u64 = 1 << 11;
+
+/// Enable the no-execute bit in page table entries, if the processor supports it.
+/// Returns whether it is enabled.
+pub unsafe fn enable_no_execute() -> bool {
+    if crate::cpuid::has_no_execute() {
+        EFER.write(EFER.read() | EFER_NXE);
+    }
+    no_execute_enabled()
+}
+
+/// Return whether the no-execute bit in page table entries is enabled.
+pub unsafe fn no_execute_enabled() -> bool {
+    EFER.read() & EFER_NXE != 0
+}
+
 /// A model-specific register.
 pub struct Msr(pub u32);
 
@@ -0,0 +1,228 @@
+//! A general purpose heap over a contiguous range of memory that can grow at its top.
+//!
+//! Free memory is kept in a list of blocks sorted by address, each block storing its size and
+//! successor in its first bytes. Allocations are first fit, and freed blocks are merged with their
+//! neighbours. All blocks are multiples of `HEAP_GRANULARITY` in size and alignment, so that any
+//! remainder of a block is large enough for holding a list entry again.
+
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+
+use amd64::{Alignable, VirtAddr};
+
+/// Size and alignment of the smallest block handed out by the heap.
+pub const HEAP_GRANULARITY: usize = 16;
+
+/// The list entry stored at the start of every free block.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+assert_eq_size!(free_block_size; FreeBlock, [u64; 2]);
+
+pub struct Heap {
+    start: VirtAddr,
+    top: VirtAddr,
+    /// The free block with the lowest address.
+    free: *mut FreeBlock,
+}
+
+// The heap exclusively owns the memory it manages.
+unsafe impl Send for Heap {}
+
+impl Heap {
+    /// Create a heap without any memory, which will start at the given address once extended.
+    pub const fn empty(start: VirtAddr) -> Heap {
+        Heap {
+            start: start,
+            top: start,
+            free: ptr::null_mut(),
+        }
+    }
+
+    /// The start of the memory managed by the heap.
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// The first address after the memory managed by the heap.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Number of bytes managed by the heap.
+    pub fn size(&self) -> usize {
+        self.top.0 - self.start.0
+    }
+
+    /// Number of bytes that are not allocated.
+    pub fn free_bytes(&self) -> usize {
+        let mut bytes = 0;
+        let mut block = self.free;
+        while ! block.is_null() {
+            unsafe {
+                bytes += (*block).size;
+                block = (*block).next;
+            }
+        }
+        bytes
+    }
+
+    /// Add the memory directly above the top to the heap.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be usable and must not be used by anything else.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap start or the amount is not a multiple of `HEAP_GRANULARITY`.
+    pub unsafe fn extend(&mut self, bytes: usize) {
+        assert!(self.start.is_aligned(HEAP_GRANULARITY) && bytes.is_aligned(HEAP_GRANULARITY),
+            "heap memory must be aligned to {} bytes", HEAP_GRANULARITY);
+        let old_top = self.top;
+        self.top += bytes;
+        self.insert_free(old_top.0, bytes);
+    }
+
+    /// Allocate a block of memory for the given layout.
+    /// Returns `None` if no free block is large enough.
+    pub unsafe fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let size = block_size(layout);
+        let align = layout.align().max(HEAP_GRANULARITY);
+        let mut link: *mut *mut FreeBlock = &mut self.free;
+        while ! (*link).is_null() {
+            let block = *link;
+            let block_start = block as usize;
+            let block_end = block_start + (*block).size;
+            let start = block_start.align_up(align);
+            let end = start + size;
+            if end <= block_end {
+                // whatever remains on either side of the allocation stays free
+                let after = if end < block_end {
+                    let rest = end as *mut FreeBlock;
+                    rest.write(FreeBlock { size: block_end - end, next: (*block).next });
+                    rest
+                } else {
+                    (*block).next
+                };
+                if start > block_start {
+                    (*block).size = start - block_start;
+                    (*block).next = after;
+                } else {
+                    *link = after;
+                }
+                return Some(NonNull::new_unchecked(start as *mut u8));
+            }
+            link = &mut (*block).next;
+        }
+        None
+    }
+
+    /// Return a block allocated with the given layout to the heap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the block overlaps free memory, e.g. because it has been freed already.
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.insert_free(ptr.as_ptr() as usize, block_size(layout));
+    }
+
+    /// Insert a block into the free list, merging it with adjacent free blocks.
+    unsafe fn insert_free(&mut self, start: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.free;
+        while ! next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+        let prev_end = if prev.is_null() { self.start.0 } else { prev as usize + (*prev).size };
+        let next_start = if next.is_null() { self.top.0 } else { next as usize };
+        assert!(prev_end <= start && start + size <= next_start, "heap block {:#x} overlaps free memory", start);
+
+        let (size, next) = if ! next.is_null() && start + size == next_start {
+            (size + (*next).size, (*next).next)
+        } else {
+            (size, next)
+        };
+        if ! prev.is_null() && prev_end == start {
+            (*prev).size += size;
+            (*prev).next = next;
+        } else {
+            let block = start as *mut FreeBlock;
+            block.write(FreeBlock { size: size, next: next });
+            if prev.is_null() {
+                self.free = block;
+            } else {
+                (*prev).next = block;
+            }
+        }
+    }
+}
+
+/// The size of the block used for an allocation with the given layout.
+fn block_size(layout: Layout) -> usize {
+    layout.size().align_up(HEAP_GRANULARITY).max(HEAP_GRANULARITY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[repr(C, align(4096))]
+    struct Arena([u8; 4096]);
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn test_heap_alloc_dealloc() {
+        let mut arena = Box::new(Arena([0; 4096]));
+        let base = arena.0.as_mut_ptr() as usize;
+        let mut heap = Heap::empty(VirtAddr(base));
+        unsafe {
+            assert_eq!(heap.alloc(layout(8, 8)), None);
+            heap.extend(2048);
+            assert_eq!(heap.free_bytes(), 2048);
+
+            let a = heap.alloc(layout(1, 1)).unwrap();
+            let b = heap.alloc(layout(100, 8)).unwrap();
+            let c = heap.alloc(layout(64, 256)).unwrap();
+            assert_eq!(a.as_ptr() as usize, base);
+            assert_eq!(b.as_ptr() as usize, base + 16);
+            assert_eq!(c.as_ptr() as usize, base + 256);
+            // the padding in front of `c` remains usable
+            let d = heap.alloc(layout(32, 16)).unwrap();
+            assert_eq!(d.as_ptr() as usize, base + 128);
+            assert_eq!(heap.free_bytes(), 2048 - 16 - 112 - 64 - 32);
+
+            assert_eq!(heap.alloc(layout(4096, 8)), None);
+            heap.extend(2048);
+            let e = heap.alloc(layout(2048, 8)).unwrap();
+            assert_eq!(e.as_ptr() as usize, base + 320);
+
+            for (ptr, l) in vec![(b, layout(100, 8)), (d, layout(32, 16)), (a, layout(1, 1)), (e, layout(2048, 8)), (c, layout(64, 256))] {
+                heap.dealloc(ptr, l);
+            }
+            // everything has been merged into a single block again
+            assert_eq!(heap.free_bytes(), 4096);
+            assert_eq!(heap.alloc(layout(4096, 4096)).map(|p| p.as_ptr() as usize), Some(base));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_heap_double_free() {
+        let mut arena = Box::new(Arena([0; 4096]));
+        let mut heap = Heap::empty(VirtAddr(arena.0.as_mut_ptr() as usize));
+        unsafe {
+            heap.extend(4096);
+            let a = heap.alloc(layout(32, 8)).unwrap();
+            let _b = heap.alloc(layout(32, 8)).unwrap();
+            heap.dealloc(a, layout(32, 8));
+            heap.dealloc(a, layout(32, 8));
+        }
+    }
+}
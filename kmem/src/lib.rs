@@ -1,5 +1,6 @@
 #![cfg_attr(not(test), no_std)]
 #![feature(asm)]
+#![feature(const_fn)]
 #![feature(step_trait)]
 
 #[macro_use]
@@ -8,11 +9,14 @@ extern crate bitflags;
 extern crate log;
 #[macro_use]
 extern crate static_assertions;
+extern crate spin;
 
 extern crate amd64;
 
+pub mod heap;
 pub mod paging;
 pub mod physical;
+pub mod slab;
 pub mod util;
 
 /// Number of trailing zeros in a page aligned address.
@@ -21,8 +25,14 @@ pub const PAGE_ALIGN_BITS: u32 = 12;
 /// Number of trailing zeros in a large page aligned address.
 pub const LARGE_PAGE_ALIGN_BITS: u32 = 21;
 
+/// Number of trailing zeros in a huge page aligned address.
+pub const HUGE_PAGE_ALIGN_BITS: u32 = 30;
+
 /// Size of a normal physical page, $ KiB.
 pub const PAGE_SIZE: usize = 1 << PAGE_ALIGN_BITS;
 
 /// Size of a large physical page, 2 MiB
 pub const LARGE_PAGE_SIZE: usize = 1 << LARGE_PAGE_ALIGN_BITS;
+
+/// Size of a huge physical page, 1 GiB
+pub const HUGE_PAGE_SIZE: usize = 1 << HUGE_PAGE_ALIGN_BITS;
@@ -1,19 +1,33 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use amd64::{PhysAddr, VirtAddr};
 
 /// Implements translation of physical to virtual addresses for a direct mapping.
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// The virtual address range reserved for the mapping may only be partially backed by page tables,
+/// in which case only the mapped part, starting at the base, is considered to be part of the mapping.
+/// The mapped part can grow over time, so users that need to see it grow should share a `&'static` reference.
+#[derive(Debug)]
 pub struct DirectMapping {
     virtual_base: VirtAddr,
     physical_base: PhysAddr,
     size_in_bytes: usize,
+    mapped_bytes: AtomicUsize,
 }
 
 impl DirectMapping {
+    /// A direct mapping that is completely backed by page tables.
     pub const fn new(virtual_base: VirtAddr, physical_base: PhysAddr, size_in_bytes: usize) -> Self {
+        Self::partial(virtual_base, physical_base, size_in_bytes, size_in_bytes)
+    }
+
+    /// A direct mapping of which only the first `mapped_bytes` are backed by page tables.
+    pub const fn partial(virtual_base: VirtAddr, physical_base: PhysAddr, size_in_bytes: usize, mapped_bytes: usize) -> Self {
         DirectMapping {
             virtual_base: virtual_base,
             physical_base: physical_base,
-            size_in_bytes: size_in_bytes
+            size_in_bytes: size_in_bytes,
+            mapped_bytes: AtomicUsize::new(mapped_bytes),
         }
     }
 
@@ -27,29 +41,48 @@ impl DirectMapping {
         self.physical_base
     }
 
-    /// The size of the mapped range in bytes.
+    /// The size of the virtual address range reserved for the mapping in bytes.
     pub fn size_in_bytes(&self) -> usize {
         self.size_in_bytes
     }
 
+    /// The size of the part of the range that is actually mapped in bytes.
+    pub fn mapped_bytes(&self) -> usize {
+        self.mapped_bytes.load(Ordering::Acquire)
+    }
+
+    /// Set the size of the part of the range that is actually mapped.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the page tables map the first `mapped_bytes` of the range accordingly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size exceeds the reserved range.
+    pub unsafe fn set_mapped_bytes(&self, mapped_bytes: usize) {
+        assert!(mapped_bytes <= self.size_in_bytes, "direct mapping cannot exceed its reserved range");
+        self.mapped_bytes.store(mapped_bytes, Ordering::Release)
+    }
+
     /// Returns whether the given physical address is part of this mapping.
     pub fn contains_phys(&self, phys_addr: PhysAddr) -> bool {
-        phys_addr >= self.physical_base && phys_addr < self.physical_base + self.size_in_bytes
+        phys_addr >= self.physical_base && phys_addr < self.physical_base + self.mapped_bytes()
     }
 
     /// Returns whether the given virtual address is part of this mapping.
     pub fn contains_virt(&self, virt_addr: VirtAddr) -> bool {
-        virt_addr >= self.virtual_base && virt_addr < self.virtual_base + self.size_in_bytes
+        virt_addr >= self.virtual_base && virt_addr < self.virtual_base + self.mapped_bytes()
     }
 
     /// Translates a physical to a virtual address using the direct mapping.
     /// 
     /// # Panics
     /// 
-    /// 1. Panics, if the given physical address is outside of the range provided by this direct mapping.
+    /// 1. Panics, if the given address is outside of the range actually mapped by this direct mapping.
     pub fn phys_to_virt(&self, phys_addr: PhysAddr) -> VirtAddr {
         if ! self.contains_phys(phys_addr) {
-            panic!("[DirectMapping::phys_to_virt] physical address {:p} out of bounds", phys_addr);
+            panic!("[DirectMapping::phys_to_virt] physical address {:p} not mapped", phys_addr);
         }
         VirtAddr(phys_addr.0 - self.physical_base.0 + self.virtual_base.0)
     }
@@ -58,10 +91,10 @@ impl DirectMapping {
     /// 
     /// # Panics
     /// 
-    /// 1. Panics, if the given physical address is outside of the range provided by this direct mapping.
+    /// 1. Panics, if the given address is outside of the range actually mapped by this direct mapping.
     pub fn virt_to_phys(&self, virt_addr: VirtAddr) -> PhysAddr {
         if ! self.contains_virt(virt_addr) {
-            panic!("[DirectMapping::virt_to_phys] virtual address {:p} out of bounds", virt_addr);
+            panic!("[DirectMapping::virt_to_phys] virtual address {:p} not mapped", virt_addr);
         }
         PhysAddr(virt_addr.0 - self.virtual_base.0 + self.physical_base.0)
     }
@@ -96,4 +129,16 @@ mod tests {
         assert!(!dm.contains_phys(low_phys - 1));
         assert!(!dm.contains_phys(high_phys));
     }
+
+    #[test]
+    fn partial_direct_mapping() {
+        let dm = DirectMapping::partial(VirtAddr(0xFFFF800000000000), PhysAddr(0), 1 << 39, 1 << 32);
+        assert!(dm.contains_phys(PhysAddr(0xFFFF_FFFF)));
+        assert!(!dm.contains_phys(PhysAddr(1 << 32)));
+        assert!(!dm.contains_virt(VirtAddr(0xFFFF800100000000)));
+
+        unsafe { dm.set_mapped_bytes(1 << 33) };
+        assert!(dm.contains_phys(PhysAddr(1 << 32)));
+        assert_eq!(dm.virt_to_phys(VirtAddr(0xFFFF800100000000)), PhysAddr(1 << 32));
+    }
 }
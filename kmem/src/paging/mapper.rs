@@ -0,0 +1,459 @@
+//! Editing page table hierarchies independently of how the page tables themselves are reached.
+//!
+//! The `RecursiveMapper` edits the active address space through the recursive PML4 slot, while the
+//! `DirectMapper` reaches the tables of any address space through the direct mapping of physical memory.
+//! The `ActiveMapper` edits the active address space with the former until the direct mapping covers
+//! all page tables, and with the latter afterwards, so that the recursive slot can be given up.
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use amd64::{Alignable, PhysAddr, VirtAddr};
+
+use super::{entry_at_level, index_at_level, invalidate_address, table_at_level, KERNEL_HALF_START_INDEX};
+use super::{MapError, MapFlags, MappingLevel};
+use super::direct::DirectMapping;
+use super::tables::{self, PageTable, PageTableEntry};
+use super::walk::{self, Translation};
+use crate::physical::PageFrame;
+use crate::physical::alloc::PageFrameAllocator;
+use crate::physical::mgmt::PageFrameOwner;
+
+/// Access to a page table hierarchy.
+///
+/// Implementations only need to provide access to the page table entries,
+/// all operations on the hierarchy are built on top of that.
+pub trait Mapper {
+    /// Return a pointer to the entry at the given level (0 is PT, 3 is PML4) that is responsible for the address.
+    /// The entries at all higher levels must be present and refer to tables.
+    unsafe fn entry(&self, level: u8, vaddr: VirtAddr) -> *mut PageTableEntry;
+
+    /// Called whenever the table at the given level for the address has been added or removed.
+    unsafe fn table_changed(&self, level: u8, vaddr: VirtAddr);
+
+    /// Called after the mapping of the address has changed, for removing stale TLB entries.
+    unsafe fn invalidate(&self, vaddr: VirtAddr);
+
+    /// Map a virtual address to the given physical address.
+    /// The given page frame allocator is used for allocating additional page tables.
+    unsafe fn mmap(&self, vaddr: VirtAddr, paddr: PhysAddr, level: MappingLevel, flags: MapFlags, pfa: &mut PageFrameAllocator) -> Result<(), MapError> {
+        // ensure address is correctly aligned
+        let (required_alignment, mapping_level) = match level {
+            MappingLevel::Page4K => (crate::PAGE_SIZE, 0),
+            MappingLevel::Page2M => (crate::LARGE_PAGE_SIZE, 1),
+            MappingLevel::Page1G => {
+                assert!(amd64::cpuid::has_1gb_pages(), "1 GiB pages are not supported by the processor");
+                (crate::HUGE_PAGE_SIZE, 2)
+            },
+        };
+        assert!(paddr.is_aligned(required_alignment));
+        assert!(vaddr.is_aligned(required_alignment));
+        trace!("[VMM] mmap({:p}, {:p}, {:?})", vaddr, paddr, flags);
+        // make sure the PDP, PD and PT tables exist
+        for i in 0..4 {
+            let current_level = 3 - i;
+            let entry: &mut PageTableEntry = &mut *self.entry(current_level, vaddr);
+            if ! entry.flags().contains(tables::Flags::PRESENT) {
+                if current_level > mapping_level {
+                    trace!("[VMM] allocating new page table at level {}", current_level);
+                    // no entry on that level yet, allocate a table
+                    let new_table = pfa.alloc().ok_or(MapError::OutOfMemory)?;
+                    pfa.set_frame_owner(new_table, PageFrameOwner::PageTable);
+                    // and assign it to the entry
+                    let mut new_entry = PageTableEntry::new();
+                    new_entry.set_base(new_table.start_address());
+                    new_entry.set_flags(tables::Flags::PRESENT | tables::Flags::WRITABLE);
+                    flags.allow(&mut new_entry);
+                    *entry = new_entry;
+                    // make sure it's available
+                    self.table_changed(current_level - 1, vaddr);
+                    // clear out page table before attempting to reference anything in it
+                    let new_table_addr = table_containing(self.entry(current_level - 1, vaddr));
+                    crate::util::memset(new_table_addr as *mut u8, crate::PAGE_SIZE, 0)
+                } else {
+                    trace!("[VMM] setting entry at level {}", current_level);
+                    // set the page table entry
+                    let mut new_entry = PageTableEntry::new();
+                    new_entry.set_base(paddr);
+                    flags.apply(&mut new_entry, mapping_level);
+                    *entry = new_entry;
+                    self.invalidate(vaddr);
+                    return Ok(());
+                }
+            } else if current_level == mapping_level || (current_level < 3 && entry.flags().contains(tables::Flags::SIZE)) {
+                // either the entry is taken, or it has a table for smaller pages, or the address is inside a larger page
+                return Err(MapError::AlreadyMapped(vaddr));
+            } else {
+                flags.allow(entry);
+            }
+        }
+        unreachable!()
+    }
+
+    /// Unmap a virtual address, which must be the start of a mapping.
+    /// Returns the physical address and size of the removed mapping, or `None` if the address was not mapped.
+    /// The physical memory that was mapped is not freed, that is up to the caller.
+    /// Page tables that are no longer used are returned to the given page frame allocator.
+    unsafe fn unmmap(&self, vaddr: VirtAddr, pfa: &mut PageFrameAllocator) -> Option<(PhysAddr, MappingLevel)> {
+        trace!("[VMM] unmmap({:p})", vaddr);
+        let mapping_level = find_mapping_level(self, vaddr)?;
+
+        let level = MappingLevel::from_table_level(mapping_level);
+        let required_alignment = level.size();
+        assert!(vaddr.is_aligned(required_alignment), "Address is not the start of a mapping");
+
+        // remove the mapping
+        let entry: &mut PageTableEntry = &mut *self.entry(mapping_level, vaddr);
+        let paddr = entry.base().align_down(required_alignment);
+        *entry = PageTableEntry::new();
+        self.invalidate(vaddr);
+
+        // free the tables that became empty, going upwards until the PDP
+        for table_level in mapping_level..3 {
+            let table: &PageTable = &*table_containing(self.entry(table_level, vaddr));
+            if ! table.is_unused() || (table_level == 2 && index_at_level(3, vaddr) >= KERNEL_HALF_START_INDEX) {
+                break;
+            }
+            trace!("[VMM] freeing page table at level {}", table_level);
+            let parent_entry: &mut PageTableEntry = &mut *self.entry(table_level + 1, vaddr);
+            let table_frame = PageFrame::including(parent_entry.base());
+            *parent_entry = PageTableEntry::new();
+            self.table_changed(table_level, vaddr);
+            pfa.free(table_frame);
+        }
+
+        Some((paddr, level))
+    }
+
+    /// Map a range of `length` bytes starting at the virtual address to the physical memory at the given address,
+    /// using the largest pages the alignment of each part of the range allows.
+    /// Addresses and length must be multiples of the page size.
+    ///
+    /// If any part of the range is already mapped, nothing is mapped and an error is returned.
+    unsafe fn map_range(&self, vaddr: VirtAddr, paddr: PhysAddr, length: usize, flags: MapFlags, pfa: &mut PageFrameAllocator) -> Result<(), MapError> {
+        if ! vaddr.is_aligned(crate::PAGE_SIZE) || ! paddr.is_aligned(crate::PAGE_SIZE) || ! length.is_aligned(crate::PAGE_SIZE) {
+            return Err(MapError::Unaligned);
+        }
+        let has_1gb_pages = amd64::cpuid::has_1gb_pages();
+        let mut offset = 0;
+        while offset < length {
+            let (cur_vaddr, cur_paddr) = (vaddr + offset, paddr + offset);
+            let level = [MappingLevel::Page1G, MappingLevel::Page2M, MappingLevel::Page4K].iter().cloned()
+                .filter(|&level| level != MappingLevel::Page1G || has_1gb_pages)
+                .find(|level| cur_vaddr.is_aligned(level.size()) && cur_paddr.is_aligned(level.size()) && length - offset >= level.size())
+                .unwrap();
+            if let Err(err) = self.mmap(cur_vaddr, cur_paddr, level, flags, pfa) {
+                // undo the part that was already mapped
+                self.unmap_range(vaddr, offset, pfa).expect("cannot undo partial mapping");
+                return Err(err);
+            }
+            offset += level.size();
+        }
+        Ok(())
+    }
+
+    /// Unmap all mappings in the range of `length` bytes starting at the virtual address.
+    /// Addresses and length must be multiples of the page size.
+    /// As with `unmmap`, the mapped memory is not freed, but page tables that are no longer used are.
+    ///
+    /// If the range would only cover part of a larger page, nothing is unmapped and an error is returned.
+    unsafe fn unmap_range(&self, vaddr: VirtAddr, length: usize, pfa: &mut PageFrameAllocator) -> Result<(), MapError> {
+        if ! vaddr.is_aligned(crate::PAGE_SIZE) || ! length.is_aligned(crate::PAGE_SIZE) {
+            return Err(MapError::Unaligned);
+        }
+        if length == 0 {
+            return Ok(());
+        }
+        // only the pages at the borders can stick out of the range, whose end may be the end of the
+        // address space, so the last page is compared instead
+        let last = vaddr + (length - crate::PAGE_SIZE);
+        for &addr in [vaddr, last].iter() {
+            if let Some(level) = find_mapping_level(self, addr) {
+                let page_size = MappingLevel::from_table_level(level).size();
+                let page_start = addr.align_down(page_size);
+                if page_start < vaddr || page_start + (page_size - crate::PAGE_SIZE) > last {
+                    return Err(MapError::PartialPage(addr));
+                }
+            }
+        }
+
+        let mut cur_vaddr = vaddr;
+        loop {
+            let (level, present) = walk_to_leaf(self, cur_vaddr);
+            if present {
+                self.unmmap(cur_vaddr, pfa);
+            }
+            // skip the whole area covered by a missing entry
+            let span = crate::PAGE_SIZE << (9 * level as usize);
+            match cur_vaddr.align_down(span).0.checked_add(span) {
+                Some(next) if next <= last.0 => cur_vaddr = VirtAddr(next),
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the flags of an existing mapping containing the virtual address.
+    /// Returns the size of the mapping that was changed, or `None` if the address was not mapped.
+    unsafe fn protect(&self, vaddr: VirtAddr, flags: MapFlags) -> Option<MappingLevel> {
+        trace!("[VMM] protect({:p}, {:?})", vaddr, flags);
+        let mapping_level = find_mapping_level(self, vaddr)?;
+        for current_level in (mapping_level + 1)..4 {
+            flags.allow(&mut *self.entry(current_level, vaddr));
+        }
+        flags.apply(&mut *self.entry(mapping_level, vaddr), mapping_level);
+        self.invalidate(vaddr);
+        Some(MappingLevel::from_table_level(mapping_level))
+    }
+
+    /// Translate a virtual address, see `kmem::paging::walk::translate`.
+    unsafe fn translate(&self, vaddr: VirtAddr) -> Option<Translation> {
+        walk::translate_with(self, vaddr)
+    }
+
+    /// Whether the tables are reached through the recursive mapping, which then occupies
+    /// the PML4 entry at `PML4_RECURSIVE_MAPPING_INDEX`.
+    fn uses_recursive_mapping(&self) -> bool {
+        false
+    }
+}
+
+/// Edits the active page tables through the recursive mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecursiveMapper;
+
+impl Mapper for RecursiveMapper {
+    unsafe fn entry(&self, level: u8, vaddr: VirtAddr) -> *mut PageTableEntry {
+        entry_at_level(level, vaddr).as_mut_ptr()
+    }
+
+    unsafe fn table_changed(&self, level: u8, vaddr: VirtAddr) {
+        // the recursive mapping of the table now refers to a different page
+        invalidate_address(table_at_level(level, vaddr));
+    }
+
+    unsafe fn invalidate(&self, vaddr: VirtAddr) {
+        invalidate_address(vaddr);
+    }
+
+    fn uses_recursive_mapping(&self) -> bool {
+        true
+    }
+}
+
+/// The direct mapping through which the `ActiveMapper` reaches the page tables, null until it covers all of them.
+static ACTIVE_DIRECT_MAPPING: AtomicPtr<DirectMapping> = AtomicPtr::new(ptr::null_mut());
+
+/// Make the `ActiveMapper` reach the page tables through the direct mapping instead of the recursive mapping.
+///
+/// # Safety
+///
+/// The direct mapping must cover all page tables of every address space that becomes active from now on.
+pub unsafe fn use_direct_mapping(mapping: &'static DirectMapping) {
+    ACTIVE_DIRECT_MAPPING.store(mapping as *const DirectMapping as *mut DirectMapping, Ordering::Release);
+}
+
+/// Edits the page tables that are active on this CPU, through the recursive mapping until
+/// `use_direct_mapping` has been called, and through the direct mapping afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveMapper;
+
+impl ActiveMapper {
+    fn direct_mapping(&self) -> Option<&'static DirectMapping> {
+        unsafe { ACTIVE_DIRECT_MAPPING.load(Ordering::Acquire).as_ref() }
+    }
+}
+
+impl Mapper for ActiveMapper {
+    unsafe fn entry(&self, level: u8, vaddr: VirtAddr) -> *mut PageTableEntry {
+        match self.direct_mapping() {
+            Some(mapping) => DirectMapper::active(mapping).entry(level, vaddr),
+            None => RecursiveMapper.entry(level, vaddr),
+        }
+    }
+
+    unsafe fn table_changed(&self, level: u8, vaddr: VirtAddr) {
+        if self.direct_mapping().is_none() {
+            RecursiveMapper.table_changed(level, vaddr);
+        }
+    }
+
+    unsafe fn invalidate(&self, vaddr: VirtAddr) {
+        invalidate_address(vaddr);
+    }
+
+    fn uses_recursive_mapping(&self) -> bool {
+        self.direct_mapping().is_none()
+    }
+}
+
+/// Edits the page tables of any address space by accessing them through the direct mapping,
+/// which must cover all page tables of the hierarchy.
+#[derive(Debug, Clone, Copy)]
+pub struct DirectMapper {
+    pml4: PhysAddr,
+    mapping: &'static DirectMapping,
+    /// Whether TLB entries need to be invalidated, i.e. whether the hierarchy is active on this CPU.
+    invalidate_tlb: bool,
+}
+
+impl DirectMapper {
+    /// Edit the inactive page table hierarchy with the given PML4.
+    /// NOTE: TLB entries are not invalidated, which also applies to changes in tables shared with the active hierarchy.
+    pub fn new(pml4: PhysAddr, mapping: &'static DirectMapping) -> DirectMapper {
+        DirectMapper {
+            pml4: pml4,
+            mapping: mapping,
+            invalidate_tlb: false,
+        }
+    }
+
+    /// Edit the page table hierarchy that is active on this CPU.
+    pub unsafe fn active(mapping: &'static DirectMapping) -> DirectMapper {
+        DirectMapper {
+            pml4: amd64::cr::read_cr3(),
+            mapping: mapping,
+            invalidate_tlb: true,
+        }
+    }
+
+    /// The physical address of the PML4 of the edited hierarchy.
+    pub fn pml4(&self) -> PhysAddr {
+        self.pml4
+    }
+}
+
+impl Mapper for DirectMapper {
+    unsafe fn entry(&self, level: u8, vaddr: VirtAddr) -> *mut PageTableEntry {
+        let mut table: *mut PageTable = self.mapping.phys_to_virt(self.pml4).as_mut_ptr();
+        for current_level in ((level + 1)..4).rev() {
+            let entry = (*table).entry(index_at_level(current_level, vaddr));
+            table = self.mapping.phys_to_virt(entry.base()).as_mut_ptr();
+        }
+        (*table).entry_mut(index_at_level(level, vaddr))
+    }
+
+    unsafe fn table_changed(&self, _level: u8, _vaddr: VirtAddr) {
+        // the direct mapping of page tables never changes
+    }
+
+    unsafe fn invalidate(&self, vaddr: VirtAddr) {
+        if self.invalidate_tlb {
+            invalidate_address(vaddr);
+        }
+    }
+}
+
+/// Return the page table containing the entry.
+fn table_containing(entry: *mut PageTableEntry) -> *mut PageTable {
+    (entry as usize).align_down(crate::PAGE_SIZE) as *mut PageTable
+}
+
+/// Find the level of the entry mapping the given virtual address (0 is PT, 1 is PD, 2 is PDP),
+/// or `None` if the address is not mapped.
+unsafe fn find_mapping_level<M: Mapper + ?Sized>(mapper: &M, vaddr: VirtAddr) -> Option<u8> {
+    match walk_to_leaf(mapper, vaddr) {
+        (level, true) => Some(level),
+        (_, false) => None,
+    }
+}
+
+/// Walk down the page tables for the given virtual address until reaching either an entry that is not present,
+/// or an entry mapping a page. Returns the level of that entry, and whether it is present.
+pub(crate) unsafe fn walk_to_leaf<M: Mapper + ?Sized>(mapper: &M, vaddr: VirtAddr) -> (u8, bool) {
+    let mut level = 3;
+    loop {
+        let entry: &PageTableEntry = &*mapper.entry(level, vaddr);
+        if ! entry.flags().contains(tables::Flags::PRESENT) {
+            return (level, false);
+        }
+        if level == 0 || (level < 3 && entry.flags().contains(tables::Flags::SIZE)) {
+            return (level, true);
+        }
+        level -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::physical::alloc::BuddyPageFrameAllocator;
+    use crate::physical::mgmt::{PageFrameInfo, PageFrameTable};
+
+    /// A page aligned block of memory simulating physical memory.
+    #[repr(C, align(4096))]
+    struct Frame([u8; 4096]);
+
+    const FRAMES: usize = 64;
+
+    /// Host memory standing in for physical memory, with an empty PML4 in frame 0, together with
+    /// a mapper for it and an allocator for the remaining frames.
+    fn environment(memory: &mut Vec<Frame>, storage: &mut Vec<PageFrameInfo>) -> (DirectMapper, BuddyPageFrameAllocator) {
+        memory.extend((0..FRAMES).map(|_| Frame([0; 4096])));
+        storage.reserve_exact(FRAMES);
+        let mapping: &'static DirectMapping = Box::leak(Box::new(
+            DirectMapping::new(VirtAddr(memory.as_mut_ptr() as usize), PhysAddr(0), FRAMES * crate::PAGE_SIZE)));
+        let pfa = unsafe {
+            let mut pft = PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), FRAMES);
+            pft.mark_allocated(crate::physical::PageFrameRegion { start: PageFrame(0), end: PageFrame(1) },
+                PageFrameOwner::PageTable);
+            BuddyPageFrameAllocator::new(pft)
+        };
+        (DirectMapper::new(PhysAddr(0), mapping), pfa)
+    }
+
+    #[test]
+    fn test_direct_mapper() {
+        let (mut memory, mut storage) = (Vec::new(), Vec::new());
+        let (mapper, mut pfa) = environment(&mut memory, &mut storage);
+        let vaddr = VirtAddr(0x40_0000_0000);
+
+        unsafe {
+            mapper.mmap(vaddr, PhysAddr(0x3F000), MappingLevel::Page4K, MapFlags::KERNEL_DATA, &mut pfa).unwrap();
+            // PDP, PD and PT were allocated
+            assert_eq!(pfa.free_count(), FRAMES - 4);
+            assert_eq!(mapper.mmap(vaddr, PhysAddr(0x3E000), MappingLevel::Page4K, MapFlags::KERNEL_DATA, &mut pfa),
+                Err(MapError::AlreadyMapped(vaddr)));
+
+            let translation = mapper.translate(vaddr + 0x123).unwrap();
+            assert_eq!(translation.paddr, PhysAddr(0x3F123));
+            assert_eq!(translation.flags, MapFlags::KERNEL_DATA);
+
+            mapper.protect(vaddr, MapFlags::USER | MapFlags::EXECUTABLE).unwrap();
+            assert_eq!(mapper.translate(vaddr).unwrap().flags, MapFlags::USER | MapFlags::EXECUTABLE);
+
+            assert_eq!(mapper.unmmap(vaddr, &mut pfa), Some((PhysAddr(0x3F000), MappingLevel::Page4K)));
+            assert_eq!(mapper.translate(vaddr), None);
+            // all tables were reclaimed
+            assert_eq!(pfa.free_count(), FRAMES - 1);
+            assert!((*mapping_table(&mapper)).is_unused());
+        }
+    }
+
+    #[test]
+    fn test_unmap_range_at_end_of_address_space() {
+        let (mut memory, mut storage) = (Vec::new(), Vec::new());
+        let (mapper, mut pfa) = environment(&mut memory, &mut storage);
+        let top_slot = VirtAddr(0xFFFF_FF80_0000_0000);
+        let last_page = VirtAddr(0xFFFF_FFFF_FFFF_F000);
+
+        unsafe {
+            mapper.mmap(top_slot, PhysAddr(0x3F000), MappingLevel::Page4K, MapFlags::KERNEL_DATA, &mut pfa).unwrap();
+            mapper.mmap(last_page, PhysAddr(0x3E000), MappingLevel::Page4K, MapFlags::KERNEL_DATA, &mut pfa).unwrap();
+            // the last two pages
+            mapper.unmap_range(last_page - crate::PAGE_SIZE, 2 * crate::PAGE_SIZE, &mut pfa).unwrap();
+            assert_eq!(mapper.translate(last_page), None);
+            assert!(mapper.translate(top_slot).is_some());
+
+            // the whole top PML4 slot, mostly skipping missing tables
+            mapper.mmap(last_page, PhysAddr(0x3E000), MappingLevel::Page4K, MapFlags::KERNEL_DATA, &mut pfa).unwrap();
+            mapper.unmap_range(top_slot, 1 << 39, &mut pfa).unwrap();
+            assert_eq!(mapper.translate(top_slot), None);
+            assert_eq!(mapper.translate(last_page), None);
+        }
+    }
+
+    unsafe fn mapping_table(mapper: &DirectMapper) -> *const PageTable {
+        mapper.mapping.phys_to_virt(mapper.pml4).as_ptr()
+    }
+}
@@ -1,76 +1,169 @@
 //! This module provides functionality for manipulating page tables.
 //! 
-//! TODO: deallocate page tables when they become unused
+//! Page tables are allocated on demand when mapping, and returned to the page frame allocator
+//! as soon as unmapping leaves them without any entries. The PML4 itself is never freed, and
+//! neither are the PDP tables of the kernel half, since they are shared between address spaces.
 
 pub mod direct;
+pub mod mapper;
+pub mod space;
 pub mod tables;
+pub mod vrange;
+pub mod walk;
 
-use self::tables::{PageTableEntry};
+use self::mapper::{ActiveMapper, Mapper};
+use self::tables::PageTableEntry;
 use crate::physical::alloc::PageFrameAllocator;
 use amd64::{Alignable, PhysAddr, VirtAddr};
 
-/// Index into the PML4 where it recursively maps onto itself.
+/// Index into the PML4 where it recursively maps onto itself, as long as the active page tables are
+/// reached through the recursive mapping, see `mapper::ActiveMapper`.
 pub const PML4_RECURSIVE_MAPPING_INDEX: usize = 510;
 
+/// Index of the first PML4 entry belonging to the kernel half of the address space.
+pub const KERNEL_HALF_START_INDEX: usize = 256;
+
 #[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug)]
 pub enum MappingLevel {
     /// Map a 4 KiB page
     Page4K,
     /// Map a 2 MiB page
-    Page2M
+    Page2M,
+    /// Map a 1 GiB page, only available if `amd64::cpuid::has_1gb_pages` says so
+    Page1G,
+}
+
+impl MappingLevel {
+    /// The number of bytes mapped at this level.
+    pub fn size(self) -> usize {
+        crate::PAGE_SIZE << (9 * self.table_level())
+    }
+
+    /// The level of the page table holding the entry of such a mapping (0 is PT, 3 is PML4).
+    fn table_level(self) -> u8 {
+        match self {
+            MappingLevel::Page4K => 0,
+            MappingLevel::Page2M => 1,
+            MappingLevel::Page1G => 2,
+        }
+    }
+
+    fn from_table_level(table_level: u8) -> MappingLevel {
+        match table_level {
+            0 => MappingLevel::Page4K,
+            1 => MappingLevel::Page2M,
+            2 => MappingLevel::Page1G,
+            _ => panic!("no mappings at table level {}", table_level),
+        }
+    }
 }
 
-/// Map a virtual address to the given physical address.
-/// The given page frame allocator is used for allocating additional page tables.
-pub unsafe fn mmap(vaddr: VirtAddr, paddr: PhysAddr, level: MappingLevel, pfa: &mut PageFrameAllocator) {
-    // ensure address is correctly aligned
-    let (required_alignment, mapping_level) = match level {
-        MappingLevel::Page4K => (crate::PAGE_SIZE, 0),
-        MappingLevel::Page2M => (crate::LARGE_PAGE_SIZE, 1),
-    };
-    assert!(paddr.is_aligned(required_alignment));
-    assert!(vaddr.is_aligned(required_alignment));
-    trace!("[VMM] mmap({:p}, {:p})", vaddr, paddr);
-    // make sure the PDP, PD and PT tables exist
-    for i in 0..4 {
-        let current_level = 3 - i;
-        let entry_addr = entry_at_level(current_level, vaddr);
-        let entry: &mut PageTableEntry = &mut *entry_addr.as_mut_ptr();
-        if ! entry.flags().contains(tables::Flags::PRESENT) {
-            if current_level > mapping_level {
-                trace!("[VMM] allocating new page table at level {}", current_level);
-                // no entry on that level yet, allocate a table
-                let new_table = pfa.alloc().expect("VMM out of memory");
-                // and assign it to the entry
-                let mut new_entry = PageTableEntry::new();
-                new_entry.set_base(new_table.start_address());
-                new_entry.set_flags(tables::Flags::PRESENT | tables::Flags::WRITABLE);
-                *entry = new_entry;
-                // make sure it's available
-                let new_table_addr = table_at_level(current_level - 1, vaddr);
-                invalidate_address(new_table_addr);
-                // clear out page table before attempting to reference anything in it
-                crate::util::memset(new_table_addr.as_mut_ptr(), crate::PAGE_SIZE, 0)
-            } else {
-                trace!("[VMM] setting entry at level {}", current_level);
-                // set the page table entry
-                let mut new_entry = PageTableEntry::new();
-                new_entry.set_base(paddr);
-                new_entry.set_flags(tables::Flags::PRESENT | tables::Flags::WRITABLE);
-                *entry = new_entry;
-                invalidate_address(vaddr);
-                break;
-            }
-        } else if current_level > 0 && entry.flags().contains(tables::Flags::SIZE) {
-            panic!("Address already mapped at a conflicting size")
+bitflags! {
+    /// Access permissions and caching behaviour of a mapping.
+    pub struct MapFlags : u8 {
+        /// The mapped memory can be written to.
+        const WRITABLE      = 0b000001;
+        /// The mapped memory is accessible from user mode.
+        const USER          = 0b000010;
+        /// Code can be executed from the mapped memory.
+        /// NOTE: mapping without this flag requires no-execute support to be enabled.
+        const EXECUTABLE    = 0b000100;
+        /// Use write-through instead of write-back caching (PWT).
+        const WRITE_THROUGH = 0b001000;
+        /// Disable caching, e.g. for MMIO (PCD).
+        const NO_CACHE      = 0b010000;
+        /// Select the memory type from the upper half of the page attribute table (PAT).
+        const PAT           = 0b100000;
+    }
+}
+
+impl MapFlags {
+    /// Flags suitable for mapping kernel data.
+    pub const KERNEL_DATA: MapFlags = MapFlags { bits: MapFlags::WRITABLE.bits };
+
+    /// Flags suitable for mapping memory mapped IO registers.
+    pub const MMIO: MapFlags = MapFlags { bits: MapFlags::WRITABLE.bits | MapFlags::NO_CACHE.bits };
+
+    /// Write the flags to the entry mapping a page at the given level (0 is PT, 1 is PD, 2 is PDP),
+    /// keeping the physical address and the user data.
+    fn apply(self, entry: &mut PageTableEntry, mapping_level: u8) {
+        let mut flags = tables::Flags::PRESENT;
+        flags.set(tables::Flags::WRITABLE, self.contains(MapFlags::WRITABLE));
+        flags.set(tables::Flags::USER, self.contains(MapFlags::USER));
+        flags.set(tables::Flags::PWT, self.contains(MapFlags::WRITE_THROUGH));
+        flags.set(tables::Flags::PCD, self.contains(MapFlags::NO_CACHE));
+        if mapping_level == 0 {
+            // in a PTE, the PAT bit takes the place of the size bit
+            flags.set(tables::Flags::SIZE, self.contains(MapFlags::PAT));
+        } else {
+            flags.insert(tables::Flags::SIZE);
+            entry.set_large_pat(self.contains(MapFlags::PAT));
+        }
+        // keep the bits maintained by the CPU
+        flags.insert(entry.flags() & (tables::Flags::ACCESSED | tables::Flags::DIRTY));
+        entry.set_flags(flags);
+        entry.set_executable(self.contains(MapFlags::EXECUTABLE));
+    }
+
+    /// Read the flags from the entry mapping a page at the given level (0 is PT, 1 is PD, 2 is PDP).
+    fn from_entry(entry: &PageTableEntry, mapping_level: u8) -> MapFlags {
+        let entry_flags = entry.flags();
+        let mut flags = MapFlags::empty();
+        flags.set(MapFlags::WRITABLE, entry_flags.contains(tables::Flags::WRITABLE));
+        flags.set(MapFlags::USER, entry_flags.contains(tables::Flags::USER));
+        flags.set(MapFlags::EXECUTABLE, entry.executable());
+        flags.set(MapFlags::WRITE_THROUGH, entry_flags.contains(tables::Flags::PWT));
+        flags.set(MapFlags::NO_CACHE, entry_flags.contains(tables::Flags::PCD));
+        flags.set(MapFlags::PAT, if mapping_level == 0 { entry_flags.contains(tables::Flags::SIZE) } else { entry.large_pat() });
+        flags
+    }
+
+    /// Make sure that an intermediate table entry does not restrict a mapping with these flags.
+    /// The final permissions are determined by the entry mapping the page.
+    fn allow(self, entry: &mut PageTableEntry) {
+        if self.contains(MapFlags::USER) && ! entry.flags().contains(tables::Flags::USER) {
+            entry.set_flags(entry.flags() | tables::Flags::USER);
         }
     }
 }
 
-/// Unmap a virtual address.
-/// The given page frame allocator is used for allocating additional page tables.
-pub unsafe fn unmmap(_vaddr: VirtAddr) {
-    unimplemented!()
+/// The reasons why mapping memory can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The address is already part of a mapping, possibly of a different size.
+    AlreadyMapped(VirtAddr),
+    /// Unmapping the range would only remove part of the larger page containing the address.
+    PartialPage(VirtAddr),
+    /// An address or length is not a multiple of the page size.
+    Unaligned,
+    /// No page frame could be allocated for a page table.
+    OutOfMemory,
+}
+
+/// Map a virtual address to the given physical address in the active address space,
+/// see `Mapper::mmap`.
+pub unsafe fn mmap(vaddr: VirtAddr, paddr: PhysAddr, level: MappingLevel, flags: MapFlags, pfa: &mut PageFrameAllocator) -> Result<(), MapError> {
+    ActiveMapper.mmap(vaddr, paddr, level, flags, pfa)
+}
+
+/// Map a range of physical memory in the active address space, see `Mapper::map_range`.
+pub unsafe fn map_range(vaddr: VirtAddr, paddr: PhysAddr, length: usize, flags: MapFlags, pfa: &mut PageFrameAllocator) -> Result<(), MapError> {
+    ActiveMapper.map_range(vaddr, paddr, length, flags, pfa)
+}
+
+/// Unmap a range in the active address space, see `Mapper::unmap_range`.
+pub unsafe fn unmap_range(vaddr: VirtAddr, length: usize, pfa: &mut PageFrameAllocator) -> Result<(), MapError> {
+    ActiveMapper.unmap_range(vaddr, length, pfa)
+}
+
+/// Change the flags of an existing mapping in the active address space, see `Mapper::protect`.
+pub unsafe fn protect(vaddr: VirtAddr, flags: MapFlags) -> Option<MappingLevel> {
+    ActiveMapper.protect(vaddr, flags)
+}
+
+/// Unmap a virtual address in the active address space, see `Mapper::unmmap`.
+pub unsafe fn unmmap(vaddr: VirtAddr, pfa: &mut PageFrameAllocator) -> Option<(PhysAddr, MappingLevel)> {
+    ActiveMapper.unmmap(vaddr, pfa)
 }
 
 /// Return the index in the page table at the given level (0 is PT, 3 is PML4)
@@ -115,3 +208,51 @@ pub fn entry_at_level(level: u8, vaddr: VirtAddr) -> VirtAddr {
 pub unsafe fn invalidate_address(vaddr: VirtAddr) {
     asm!("invlpg [$0]" : : "r"(vaddr.0) : : "intel", "volatile")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::tables::Flags;
+
+    #[test]
+    fn test_mapping_levels() {
+        assert_eq!(MappingLevel::Page4K.size(), crate::PAGE_SIZE);
+        assert_eq!(MappingLevel::Page2M.size(), crate::LARGE_PAGE_SIZE);
+        assert_eq!(MappingLevel::Page1G.size(), crate::HUGE_PAGE_SIZE);
+        for level in 0..3 {
+            assert_eq!(MappingLevel::from_table_level(level).table_level(), level);
+        }
+    }
+
+    #[test]
+    fn test_map_flags() {
+        let mut entry = PageTableEntry::new();
+        entry.set_base(PhysAddr(0x1234_5000));
+        (MapFlags::USER | MapFlags::PAT).apply(&mut entry, 0);
+        assert_eq!(entry.flags(), Flags::PRESENT | Flags::USER | Flags::SIZE);
+        assert!(! entry.executable());
+        assert_eq!(entry.base(), PhysAddr(0x1234_5000));
+
+        let mut entry = PageTableEntry::new();
+        entry.set_base(PhysAddr(0x1240_0000));
+        (MapFlags::MMIO | MapFlags::EXECUTABLE | MapFlags::PAT).apply(&mut entry, 1);
+        assert_eq!(entry.flags(), Flags::PRESENT | Flags::WRITABLE | Flags::PCD | Flags::SIZE);
+        assert!(entry.executable());
+        assert!(entry.large_pat());
+        assert_eq!(MapFlags::from_entry(&entry, 1), MapFlags::MMIO | MapFlags::EXECUTABLE | MapFlags::PAT);
+
+        let mut entry = PageTableEntry::new();
+        entry.set_base(PhysAddr(0x4000_0000));
+        MapFlags::KERNEL_DATA.apply(&mut entry, 2);
+        assert_eq!(entry.flags(), Flags::PRESENT | Flags::WRITABLE | Flags::SIZE);
+        assert_eq!(entry.base(), PhysAddr(0x4000_0000));
+
+        // intermediate entries only ever get less restrictive
+        let mut entry = PageTableEntry::new();
+        entry.set_flags(Flags::PRESENT | Flags::WRITABLE);
+        MapFlags::KERNEL_DATA.allow(&mut entry);
+        assert_eq!(entry.flags(), Flags::PRESENT | Flags::WRITABLE);
+        MapFlags::USER.allow(&mut entry);
+        assert_eq!(entry.flags(), Flags::PRESENT | Flags::WRITABLE | Flags::USER);
+    }
+}
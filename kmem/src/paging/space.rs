@@ -0,0 +1,202 @@
+//! Address spaces, each with their own PML4.
+//!
+//! All address spaces share the kernel half of the virtual address space (PML4 entries 256 to 511),
+//! which is copied from the active address space when a new one is created. This only keeps them in
+//! sync if every kernel half entry already refers to a PDP table, which is why the kernel address
+//! space is set up with `populate_kernel_half` before any other is created. New address spaces do
+//! not get a recursive mapping, the active tables are reached through the direct mapping instead.
+//!
+//! The page tables of an address space are accessed through the direct mapping, so that
+//! it can be edited via the `Mapper` trait regardless of whether it is active or not.
+
+use amd64::{cr, PhysAddr, VirtAddr};
+
+use super::{invalidate_address, KERNEL_HALF_START_INDEX, PML4_RECURSIVE_MAPPING_INDEX};
+use super::direct::DirectMapping;
+use super::mapper::{DirectMapper, Mapper};
+use super::tables::{self, PageTable, PageTableEntry};
+use crate::physical::PageFrame;
+use crate::physical::alloc::PageFrameAllocator;
+use crate::physical::mgmt::PageFrameOwner;
+
+/// An address space defined by a PML4.
+pub struct AddressSpace {
+    pml4: PageFrame,
+    /// Used for accessing the page tables of the space directly.
+    mapping: &'static DirectMapping,
+}
+
+impl AddressSpace {
+    /// Allocate a fresh PML4 with an empty user half, sharing the kernel half of the active address space.
+    /// Returns `None` if no page frame could be allocated.
+    pub unsafe fn new(mapping: &'static DirectMapping, pfa: &mut PageFrameAllocator) -> Option<AddressSpace> {
+        Self::sharing_kernel_half(&Self::active(mapping), pfa)
+    }
+
+    /// Allocate a fresh PML4 with an empty user half, sharing the kernel half of the given address space.
+    /// The recursive mapping is not carried over, so the slot at `PML4_RECURSIVE_MAPPING_INDEX` stays empty.
+    unsafe fn sharing_kernel_half(template: &AddressSpace, pfa: &mut PageFrameAllocator) -> Option<AddressSpace> {
+        let pml4 = pfa.alloc()?;
+        pfa.set_frame_owner(pml4, PageFrameOwner::PageTable);
+        let space = AddressSpace { pml4: pml4, mapping: template.mapping };
+
+        let table = space.table(pml4.start_address());
+        let template_table = template.table(template.pml4.start_address());
+        for i in 0..512 {
+            *table.entry_mut(i) = if i >= KERNEL_HALF_START_INDEX && i != PML4_RECURSIVE_MAPPING_INDEX {
+                *template_table.entry(i)
+            } else {
+                PageTableEntry::new()
+            };
+        }
+
+        Some(space)
+    }
+
+    /// Give every PML4 entry of the kernel half a PDP table, so that mappings added to the kernel
+    /// half later on are seen by all address spaces created from this one afterwards. The slot of the
+    /// recursive mapping is left alone, since it is never shared.
+    /// Returns `None` if no page frame could be allocated.
+    pub unsafe fn populate_kernel_half(&self, pfa: &mut PageFrameAllocator) -> Option<()> {
+        let table = self.table(self.pml4.start_address());
+        for i in KERNEL_HALF_START_INDEX..512 {
+            if i == PML4_RECURSIVE_MAPPING_INDEX || table.entry(i).flags().contains(tables::Flags::PRESENT) {
+                continue;
+            }
+            let pdp = pfa.alloc()?;
+            pfa.set_frame_owner(pdp, PageFrameOwner::PageTable);
+            crate::util::memset(self.table(pdp.start_address()) as *mut PageTable as *mut u8, crate::PAGE_SIZE, 0);
+            let mut entry = PageTableEntry::new();
+            entry.set_base(pdp.start_address());
+            entry.set_flags(tables::Flags::PRESENT | tables::Flags::WRITABLE);
+            *table.entry_mut(i) = entry;
+        }
+        Some(())
+    }
+
+    /// The address space that is currently active on this CPU.
+    pub unsafe fn active(mapping: &'static DirectMapping) -> AddressSpace {
+        AddressSpace {
+            pml4: PageFrame::including(cr::read_cr3()),
+            mapping: mapping,
+        }
+    }
+
+    /// The page frame holding the PML4 of this address space.
+    pub fn pml4(&self) -> PageFrame {
+        self.pml4
+    }
+
+    /// Return whether this address space is the active one on this CPU.
+    pub fn is_active(&self) -> bool {
+        unsafe { PageFrame::including(cr::read_cr3()) == self.pml4 }
+    }
+
+    /// Make this address space the active one on this CPU by loading CR3.
+    pub unsafe fn activate(&self) {
+        cr::write_cr3(self.pml4.start_address());
+    }
+
+    /// A mapper for the page tables of this address space.
+    pub fn mapper(&self) -> DirectMapper {
+        DirectMapper::new(self.pml4.start_address(), self.mapping)
+    }
+
+    /// Free the PML4 and all page tables of the user half of this address space.
+    /// The frames mapped in the address space are not freed, that is up to the caller.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the address space is still active.
+    pub unsafe fn destroy(self, pfa: &mut PageFrameAllocator) {
+        assert!(! self.is_active(), "cannot destroy the active address space");
+        self.free_tables(self.pml4.start_address(), 3, 0..KERNEL_HALF_START_INDEX, pfa);
+    }
+
+    /// Free the page table at the given level along with all tables referenced by the given range of entries.
+    unsafe fn free_tables(&self, table_addr: PhysAddr, level: u8, entries: core::ops::Range<usize>, pfa: &mut PageFrameAllocator) {
+        if level > 0 {
+            let table = self.table(table_addr);
+            for i in entries {
+                let entry = *table.entry(i);
+                if entry.flags().contains(tables::Flags::PRESENT) && ! entry.flags().contains(tables::Flags::SIZE) {
+                    self.free_tables(entry.base(), level - 1, 0..512, pfa);
+                }
+            }
+        }
+        pfa.free(PageFrame::including(table_addr));
+    }
+
+    /// Access a page table through the direct mapping.
+    unsafe fn table(&self, table_addr: PhysAddr) -> &mut PageTable {
+        &mut *self.mapping.phys_to_virt(table_addr).as_mut_ptr()
+    }
+}
+
+impl Mapper for AddressSpace {
+    unsafe fn entry(&self, level: u8, vaddr: VirtAddr) -> *mut PageTableEntry {
+        self.mapper().entry(level, vaddr)
+    }
+
+    unsafe fn table_changed(&self, _level: u8, _vaddr: VirtAddr) {
+        // the direct mapping of page tables never changes
+    }
+
+    unsafe fn invalidate(&self, vaddr: VirtAddr) {
+        // the kernel half is shared with the active address space
+        if self.is_active() || vaddr.0 >= (KERNEL_HALF_START_INDEX << 39) {
+            invalidate_address(vaddr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::physical::PageFrameRegion;
+    use crate::physical::alloc::BuddyPageFrameAllocator;
+    use crate::physical::mgmt::{PageFrameInfo, PageFrameTable};
+
+    /// A page aligned block of memory simulating physical memory.
+    #[repr(C, align(4096))]
+    struct Frame([u8; 4096]);
+
+    #[test]
+    fn test_new_space_shares_kernel_half() {
+        const FRAMES: usize = 260;
+        let mut memory: Vec<Frame> = (0..FRAMES).map(|_| Frame([0; 4096])).collect();
+        let mapping: &'static DirectMapping = Box::leak(Box::new(
+            DirectMapping::new(VirtAddr(memory.as_mut_ptr() as usize), PhysAddr(0), FRAMES * crate::PAGE_SIZE)));
+        let mut storage: Vec<PageFrameInfo> = Vec::with_capacity(FRAMES);
+        let mut pfa = unsafe {
+            let mut pft = PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), FRAMES);
+            // frame 0 is the PML4 of the template, frame 1 a kernel PDP
+            pft.mark_allocated(PageFrameRegion { start: PageFrame(0), end: PageFrame(2) }, PageFrameOwner::PageTable);
+            BuddyPageFrameAllocator::new(pft)
+        };
+        let template = AddressSpace { pml4: PageFrame(0), mapping: mapping };
+        let entry = |frame: usize| {
+            let mut entry = PageTableEntry::new();
+            entry.set_base(PageFrame(frame).start_address());
+            entry.set_flags(tables::Flags::PRESENT | tables::Flags::WRITABLE);
+            entry
+        };
+
+        unsafe {
+            let template_table = template.table(PhysAddr(0));
+            *template_table.entry_mut(1) = entry(1);
+            *template_table.entry_mut(KERNEL_HALF_START_INDEX) = entry(1);
+            *template_table.entry_mut(PML4_RECURSIVE_MAPPING_INDEX) = entry(0);
+
+            let space = AddressSpace::sharing_kernel_half(&template, &mut pfa).unwrap();
+            let table = space.table(space.pml4().start_address());
+            assert!(! table.entry(1).flags().contains(tables::Flags::PRESENT));
+            assert_eq!(table.entry(KERNEL_HALF_START_INDEX).base(), PhysAddr(0x1000));
+            assert!(! table.entry(PML4_RECURSIVE_MAPPING_INDEX).flags().contains(tables::Flags::PRESENT));
+
+            // the recursive slot does not get a PDP either
+            space.populate_kernel_half(&mut pfa).unwrap();
+            assert!(! table.entry(PML4_RECURSIVE_MAPPING_INDEX).flags().contains(tables::Flags::PRESENT));
+        }
+    }
+}
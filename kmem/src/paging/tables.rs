@@ -22,6 +22,7 @@ bitflags! {
 
 impl PageTableEntry {
     const NO_EXECUTE_BIT: u32 = 63;
+    const LARGE_PAT_BIT: u32 = 12;
     // mask for valid physical base addresses
     const ADDR_MASK: usize = 0x000F_FFFF_FFFF_F000;
     const USER_DATA_HIGH_MASK: u64 = 0x7FF8_0000_0000_0000;
@@ -31,6 +32,11 @@ impl PageTableEntry {
         PageTableEntry(0)
     }
 
+    /// Return whether the entry is completely empty, i.e. neither maps anything nor holds user data.
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
     pub fn flags(&self) -> Flags {
         // unwrapping cannot fail, all combinations are valid
         Flags::from_bits((self.0 & 0xFF) as u8).unwrap()
@@ -51,6 +57,18 @@ impl PageTableEntry {
         self.set_bit(Self::NO_EXECUTE_BIT, ! executable)
     }
 
+    /// Return whether the PAT bit of an entry mapping a large page is set.
+    /// In such entries, it occupies the lowest bit of the physical address, which is otherwise unused.
+    pub fn large_pat(&self) -> bool {
+        self.check_bit(Self::LARGE_PAT_BIT)
+    }
+
+    /// Set the PAT bit of an entry mapping a large page.
+    /// NOTE: this must be done after setting the base address.
+    pub fn set_large_pat(&mut self, pat: bool) {
+        self.set_bit(Self::LARGE_PAT_BIT, pat)
+    }
+
     /// Return the physical page address of the page or page table pointed to by this entry.
     pub fn base(&self) -> PhysAddr {
         PhysAddr((self.0 as usize) & Self::ADDR_MASK)
@@ -107,6 +125,11 @@ impl PageTable {
     pub fn entry_mut(&mut self, idx: usize) -> &mut PageTableEntry {
         &mut self.entries[idx]
     }
+
+    /// Return whether all entries of the table are unused.
+    pub fn is_unused(&self) -> bool {
+        self.entries.iter().all(|e| e.is_unused())
+    }
 }
 
 #[cfg(test)]
@@ -116,6 +139,7 @@ mod test {
     #[test]
     fn page_table_entry_accessors() {
         let mut pte = PageTableEntry::new();
+        assert!(pte.is_unused());
 
         let user_data = 0x3A75; // 14 bits available
         let flags = Flags::PRESENT | Flags::SIZE | Flags::USER;
@@ -0,0 +1,213 @@
+//! Allocation of ranges of virtual address space.
+//!
+//! The allocator only hands out addresses, backing them with page frames is up to the caller.
+//! Free address space is kept as a sorted list of disjoint ranges with a fixed capacity,
+//! and allocations are served from the lowest free range that is large enough.
+
+use amd64::{Alignable, VirtAddr};
+
+use crate::PAGE_SIZE;
+
+/// Maximum number of disjoint free ranges a `VirtRangeAllocator` can keep track of.
+pub const VRANGE_FREE_CAPACITY: usize = 128;
+
+/// A page aligned range of virtual addresses handed out by a `VirtRangeAllocator`,
+/// optionally surrounded by guard pages that are not handed out to anybody else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRange {
+    start: VirtAddr,
+    page_count: usize,
+    guard_pages: usize,
+}
+
+impl VirtRange {
+    /// The first address of the range.
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// The first address after the range.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size_in_bytes()
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.page_count * PAGE_SIZE
+    }
+
+    /// Number of guard pages on either side of the range.
+    pub fn guard_pages(&self) -> usize {
+        self.guard_pages
+    }
+
+    /// The start address of the page with the given index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is outside of the range.
+    pub fn page(&self, index: usize) -> VirtAddr {
+        assert!(index < self.page_count, "page {} outside of virtual range", index);
+        self.start + index * PAGE_SIZE
+    }
+
+    /// The addresses occupied by the range, including the guard pages.
+    fn reserved(&self) -> Span {
+        let guard_size = self.guard_pages * PAGE_SIZE;
+        Span { start: self.start.0 - guard_size, end: self.end().0 + guard_size }
+    }
+}
+
+/// A range of free addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    fn length(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// Hands out page aligned ranges of virtual addresses from a fixed region.
+pub struct VirtRangeAllocator {
+    /// The region the ranges are taken from.
+    bounds: Span,
+    /// Free parts of the region, sorted, non-overlapping and non-adjacent.
+    free: [Span; VRANGE_FREE_CAPACITY],
+    free_count: usize,
+}
+
+impl VirtRangeAllocator {
+    /// Create an allocator handing out addresses from `start` to `end`, which must be page aligned.
+    pub fn new(start: VirtAddr, end: VirtAddr) -> VirtRangeAllocator {
+        assert!(start.is_aligned(PAGE_SIZE) && end.is_aligned(PAGE_SIZE), "virtual range bounds must be page aligned");
+        assert!(start <= end);
+        let bounds = Span { start: start.0, end: end.0 };
+        let mut allocator = VirtRangeAllocator {
+            bounds: bounds,
+            free: [Span { start: 0, end: 0 }; VRANGE_FREE_CAPACITY],
+            free_count: 0,
+        };
+        if start < end {
+            allocator.free[0] = bounds;
+            allocator.free_count = 1;
+        }
+        allocator
+    }
+
+    /// Number of pages that are not part of any range.
+    pub fn free_pages(&self) -> usize {
+        self.free_spans().iter().map(|s| s.length() / PAGE_SIZE).sum()
+    }
+
+    /// Allocate `page_count` pages of address space with `guard_pages` unused pages on either side.
+    /// Returns `None` if no free part of the region is large enough.
+    pub fn alloc(&mut self, page_count: usize, guard_pages: usize) -> Option<VirtRange> {
+        if page_count == 0 {
+            return None;
+        }
+        let size = (page_count + 2 * guard_pages) * PAGE_SIZE;
+        let index = self.free_spans().iter().position(|s| s.length() >= size)?;
+        let start = self.free[index].start;
+        self.free[index].start += size;
+        if self.free[index].length() == 0 {
+            self.remove_span(index);
+        }
+        Some(VirtRange {
+            start: VirtAddr(start + guard_pages * PAGE_SIZE),
+            page_count: page_count,
+            guard_pages: guard_pages,
+        })
+    }
+
+    /// Return a range handed out by `alloc`, together with its guard pages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is not part of the region or already free, or if the allocator
+    /// cannot keep track of the additional free range.
+    pub fn free(&mut self, range: VirtRange) {
+        let span = range.reserved();
+        assert!(span.start >= self.bounds.start && span.end <= self.bounds.end,
+            "virtual range {:p} - {:p} not part of the region", range.start(), range.end());
+        // the free ranges before and after the returned one
+        let next = self.free_spans().iter().position(|s| s.start >= span.end).unwrap_or(self.free_count);
+        assert!(next == 0 || self.free[next - 1].end <= span.start,
+            "virtual range {:p} - {:p} is already free", range.start(), range.end());
+        let merge_prev = next > 0 && self.free[next - 1].end == span.start;
+        let merge_next = next < self.free_count && self.free[next].start == span.end;
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.free[next - 1].end = self.free[next].end;
+                self.remove_span(next);
+            }
+            (true, false) => self.free[next - 1].end = span.end,
+            (false, true) => self.free[next].start = span.start,
+            (false, false) => {
+                assert!(self.free_count < VRANGE_FREE_CAPACITY, "virtual range allocator capacity exceeded");
+                for i in (next..self.free_count).rev() {
+                    self.free[i + 1] = self.free[i];
+                }
+                self.free[next] = span;
+                self.free_count += 1;
+            }
+        }
+    }
+
+    fn free_spans(&self) -> &[Span] {
+        &self.free[0..self.free_count]
+    }
+
+    fn remove_span(&mut self, index: usize) {
+        for i in index..self.free_count - 1 {
+            self.free[i] = self.free[i + 1];
+        }
+        self.free_count -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vrange_alloc_free() {
+        let base = 0xFFFF_FF80_0000_0000;
+        let mut vra = VirtRangeAllocator::new(VirtAddr(base), VirtAddr(base + 64 * PAGE_SIZE));
+        assert_eq!(vra.free_pages(), 64);
+
+        let a = vra.alloc(4, 1).unwrap();
+        assert_eq!(a.start(), VirtAddr(base + PAGE_SIZE));
+        assert_eq!(a.end(), VirtAddr(base + 5 * PAGE_SIZE));
+        let b = vra.alloc(10, 0).unwrap();
+        assert_eq!(b.start(), VirtAddr(base + 6 * PAGE_SIZE));
+        let c = vra.alloc(2, 2).unwrap();
+        assert_eq!(vra.free_pages(), 64 - 6 - 10 - 6);
+        assert_eq!(vra.alloc(100, 0), None);
+        assert_eq!(vra.alloc(0, 0), None);
+
+        // the gap left by `a` is reused first
+        vra.free(a);
+        assert_eq!(vra.alloc(6, 0).unwrap().start(), VirtAddr(base));
+
+        // freeing merges with the neighbours
+        vra.free(c);
+        vra.free(b);
+        assert_eq!(vra.free_spans(), &[Span { start: base + 6 * PAGE_SIZE, end: base + 64 * PAGE_SIZE }]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_vrange_double_free() {
+        let mut vra = VirtRangeAllocator::new(VirtAddr(0x1000), VirtAddr(0x10000));
+        let a = vra.alloc(2, 1).unwrap();
+        vra.free(a);
+        vra.free(a);
+    }
+}
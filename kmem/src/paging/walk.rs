@@ -0,0 +1,250 @@
+//! Inspection of page tables, by default the active ones, see `mapper::ActiveMapper`.
+//!
+//! The flags reported for a mapping are the effective ones, combining the entries of all levels:
+//! a page is only writable or user accessible if every level allows it, and only executable
+//! if no level forbids it.
+
+use amd64::{PhysAddr, VirtAddr};
+
+use super::{index_at_level, MapFlags, MappingLevel, PML4_RECURSIVE_MAPPING_INDEX};
+use super::mapper::{ActiveMapper, Mapper};
+use super::tables::{self, PageTableEntry};
+
+/// Size of the virtual address space covered by the page tables.
+const ADDRESS_SPACE_SIZE: usize = 1 << 48;
+
+/// The result of translating a virtual address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    /// The physical address the virtual address is mapped to.
+    pub paddr: PhysAddr,
+    /// The size of the page containing the address.
+    pub level: MappingLevel,
+    /// The effective flags of the page.
+    pub flags: MapFlags,
+}
+
+/// Translate a virtual address using the active page tables.
+/// Returns `None` if the address is not mapped.
+pub unsafe fn translate(vaddr: VirtAddr) -> Option<Translation> {
+    translate_with(&ActiveMapper, vaddr)
+}
+
+/// Translate a virtual address using the page tables accessed by the mapper.
+pub unsafe fn translate_with<M: Mapper + ?Sized>(mapper: &M, vaddr: VirtAddr) -> Option<Translation> {
+    let (level, entry, flags) = lookup(mapper, vaddr)?;
+    let offset = vaddr.0 & (level.size() - 1);
+    Some(Translation {
+        paddr: PhysAddr(entry.base().0 & !(level.size() - 1)) + offset,
+        level: level,
+        flags: flags,
+    })
+}
+
+/// A range of virtual memory that is mapped to a contiguous range of physical memory with the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    /// The first mapped virtual address.
+    pub vaddr: VirtAddr,
+    /// The physical address `vaddr` is mapped to.
+    pub paddr: PhysAddr,
+    /// The size of the range in bytes.
+    pub size: usize,
+    /// The effective flags of all pages in the range.
+    pub flags: MapFlags,
+}
+
+impl MappedRange {
+    /// Whether the other range directly continues this one, both virtually and physically, with the same flags.
+    fn is_continued_by(&self, other: &MappedRange) -> bool {
+        self.vaddr + self.size == other.vaddr && self.paddr + self.size == other.paddr && self.flags == other.flags
+    }
+}
+
+/// Iterate over all present mappings of the active page tables in ascending order,
+/// coalescing adjacent pages into ranges. The recursive mapping is skipped, if it is in use.
+pub unsafe fn mappings() -> Mappings<ActiveMapper> {
+    mappings_with(ActiveMapper)
+}
+
+/// Iterate over all present mappings of the page tables accessed by the mapper, see `mappings`.
+pub unsafe fn mappings_with<M: Mapper>(mapper: M) -> Mappings<M> {
+    Mappings {
+        mapper: mapper,
+        next_addr: 0,
+        pending: None,
+    }
+}
+
+/// An iterator over the mappings in a page table hierarchy, see `mappings`.
+pub struct Mappings<M> {
+    mapper: M,
+    /// The next address to look at, without the sign extension of canonical addresses.
+    next_addr: usize,
+    /// The range that is currently being coalesced.
+    pending: Option<MappedRange>,
+}
+
+impl<M: Mapper> Iterator for Mappings<M> {
+    type Item = MappedRange;
+
+    fn next(&mut self) -> Option<MappedRange> {
+        loop {
+            match unsafe { self.next_page() } {
+                Some(page) => match self.pending {
+                    Some(ref mut pending) if pending.is_continued_by(&page) => pending.size += page.size,
+                    _ => {
+                        let finished = self.pending.take();
+                        self.pending = Some(page);
+                        if finished.is_some() {
+                            return finished;
+                        }
+                    }
+                },
+                None => return self.pending.take(),
+            }
+        }
+    }
+}
+
+impl<M: Mapper> Mappings<M> {
+    /// Find the next mapped page at or after `next_addr`.
+    unsafe fn next_page(&mut self) -> Option<MappedRange> {
+        'search: while self.next_addr < ADDRESS_SPACE_SIZE {
+            let vaddr = canonical(self.next_addr);
+            let mut flags = MapFlags::WRITABLE | MapFlags::USER | MapFlags::EXECUTABLE;
+            for level in (0..4).rev() {
+                let span = crate::PAGE_SIZE << (9 * level);
+                let entry: &PageTableEntry = &*self.mapper.entry(level, vaddr);
+                let skip = level == 3 && index_at_level(3, vaddr) == PML4_RECURSIVE_MAPPING_INDEX
+                    && self.mapper.uses_recursive_mapping();
+                if skip || ! entry.flags().contains(tables::Flags::PRESENT) {
+                    self.next_addr = (self.next_addr & !(span - 1)) + span;
+                    continue 'search;
+                }
+                flags = restrict(flags, entry);
+                if level == 0 || (level < 3 && entry.flags().contains(tables::Flags::SIZE)) {
+                    self.next_addr = (self.next_addr & !(span - 1)) + span;
+                    return Some(MappedRange {
+                        vaddr: canonical(self.next_addr - span),
+                        paddr: PhysAddr(entry.base().0 & !(span - 1)),
+                        size: span,
+                        flags: flags | caching(entry, level),
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Log all present mappings of the active page tables at debug level.
+pub unsafe fn dump() {
+    debug!("[VMM] {:^18} {:^18} {:>6} {}", "Virtual", "Physical", "Size", "Flags");
+    for range in mappings() {
+        let (amount, unit) = size_with_unit(range.size);
+        debug!("[VMM] {:p} {:p} {:>5}{} {}", range.vaddr, range.paddr, amount, unit, FlagString(range.flags));
+    }
+}
+
+/// Find the entry mapping the address, along with the effective flags.
+unsafe fn lookup<M: Mapper + ?Sized>(mapper: &M, vaddr: VirtAddr) -> Option<(MappingLevel, PageTableEntry, MapFlags)> {
+    let mut flags = MapFlags::WRITABLE | MapFlags::USER | MapFlags::EXECUTABLE;
+    for level in (0..4).rev() {
+        let entry: PageTableEntry = *mapper.entry(level, vaddr);
+        if ! entry.flags().contains(tables::Flags::PRESENT) {
+            return None;
+        }
+        flags = restrict(flags, &entry);
+        if level == 0 || (level < 3 && entry.flags().contains(tables::Flags::SIZE)) {
+            return Some((MappingLevel::from_table_level(level), entry, flags | caching(&entry, level)));
+        }
+    }
+    None
+}
+
+/// Remove the permissions not granted by the entry.
+fn restrict(flags: MapFlags, entry: &PageTableEntry) -> MapFlags {
+    let mut flags = flags;
+    if ! entry.flags().contains(tables::Flags::WRITABLE) {
+        flags.remove(MapFlags::WRITABLE);
+    }
+    if ! entry.flags().contains(tables::Flags::USER) {
+        flags.remove(MapFlags::USER);
+    }
+    if ! entry.executable() {
+        flags.remove(MapFlags::EXECUTABLE);
+    }
+    flags
+}
+
+/// The caching flags of the entry mapping a page at the given level.
+fn caching(entry: &PageTableEntry, level: u8) -> MapFlags {
+    MapFlags::from_entry(entry, level) & (MapFlags::WRITE_THROUGH | MapFlags::NO_CACHE | MapFlags::PAT)
+}
+
+/// Turn a 48 bit address into a canonical virtual address by sign extending it.
+fn canonical(addr: usize) -> VirtAddr {
+    if addr & (1 << 47) != 0 {
+        VirtAddr(addr | 0xFFFF_0000_0000_0000)
+    } else {
+        VirtAddr(addr)
+    }
+}
+
+/// Split a size in bytes into an amount and the largest binary unit it is a multiple of.
+fn size_with_unit(bytes: usize) -> (usize, &'static str) {
+    if bytes % (1 << 30) == 0 {
+        (bytes >> 30, "G")
+    } else if bytes % (1 << 20) == 0 {
+        (bytes >> 20, "M")
+    } else {
+        (bytes >> 10, "K")
+    }
+}
+
+/// Compact representation of mapping flags, e.g. `rw-k` for writable kernel data.
+struct FlagString(MapFlags);
+
+impl core::fmt::Display for FlagString {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let flag = |flag, c| if self.0.contains(flag) { c } else { '-' };
+        write!(f, "r{}{}{}", flag(MapFlags::WRITABLE, 'w'), flag(MapFlags::EXECUTABLE, 'x'),
+            if self.0.contains(MapFlags::USER) { 'u' } else { 'k' })?;
+        if self.0.contains(MapFlags::NO_CACHE) {
+            write!(f, " uc")?;
+        }
+        if self.0.contains(MapFlags::WRITE_THROUGH) {
+            write!(f, " wt")?;
+        }
+        if self.0.contains(MapFlags::PAT) {
+            write!(f, " pat")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(size_with_unit(4096), (4, "K"));
+        assert_eq!(size_with_unit(6 << 20), (6, "M"));
+        assert_eq!(size_with_unit(512 << 30), (512, "G"));
+        assert_eq!(format!("{}", FlagString(MapFlags::KERNEL_DATA)), "rw-k");
+        assert_eq!(format!("{}", FlagString(MapFlags::MMIO | MapFlags::USER)), "rw-u uc");
+        assert_eq!(format!("{}", FlagString(MapFlags::EXECUTABLE)), "r-xk");
+    }
+
+    #[test]
+    fn test_coalescing() {
+        let range = |vaddr, paddr, size, flags| MappedRange { vaddr: VirtAddr(vaddr), paddr: PhysAddr(paddr), size: size, flags: flags };
+        let first = range(0x1000, 0x5000, 0x1000, MapFlags::WRITABLE);
+        assert!(first.is_continued_by(&range(0x2000, 0x6000, 0x1000, MapFlags::WRITABLE)));
+        assert!(!first.is_continued_by(&range(0x2000, 0x7000, 0x1000, MapFlags::WRITABLE)));
+        assert!(!first.is_continued_by(&range(0x2000, 0x6000, 0x1000, MapFlags::empty())));
+        assert_eq!(canonical(0x8000_0000_0000), VirtAddr(0xFFFF_8000_0000_0000));
+    }
+}
@@ -0,0 +1,398 @@
+//! A binary buddy page frame allocator.
+//!
+//! Free memory is kept as naturally aligned blocks of `2^order` page frames. There is one
+//! free list per order, and the lists are threaded through the `PageFrameInfo` entries of the
+//! first frame of each free block, so the allocator does not need any memory of its own.
+//!
+//! Allocating a block splits a larger block if necessary, freeing a block merges it with its
+//! buddy as long as the buddy is free as well. Both take `O(MAX_ORDER)` steps, plus the time
+//! needed for updating the state of the affected frames in the page frame table.
+//!
+//! Each zone has its own set of free lists. Since the zone boundaries are aligned to the largest
+//! block size, blocks never cross a zone boundary, and neither do merged buddies.
+
+use core::mem;
+
+use crate::physical::{PageFrame, PageFrameRegion};
+use crate::physical::alloc::PageFrameAllocator;
+use crate::physical::mgmt::{PageFrameTable, PageFrameState, PageFrameInfo, PageFrameOwner};
+use crate::physical::zone::Zone;
+
+/// Order of the largest block managed by the allocator (4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+/// Number of free lists.
+const ORDER_COUNT: usize = MAX_ORDER + 1;
+
+pub struct BuddyPageFrameAllocator {
+    page_frame_table: PageFrameTable,
+    /// Index of the first free block of each order, for each zone.
+    free_lists: [[u32; ORDER_COUNT]; Zone::COUNT],
+    /// Number of free page frames in each zone.
+    free_counts: [usize; Zone::COUNT],
+}
+
+impl BuddyPageFrameAllocator {
+    /// Create a new buddy allocator managing all frames that are marked as free in the given table.
+    pub fn new(page_frames: PageFrameTable) -> Self {
+        assert!(page_frames.upper_bound().0 < PageFrameInfo::NIL as usize, "too many page frames for buddy allocator");
+
+        let mut pfa = BuddyPageFrameAllocator {
+            page_frame_table: page_frames,
+            free_lists: [[PageFrameInfo::NIL; ORDER_COUNT]; Zone::COUNT],
+            free_counts: [0; Zone::COUNT],
+        };
+
+        // add all runs of free frames to the free lists
+        let upper_bound = pfa.page_frame_table.upper_bound().0;
+        let mut cur = 0;
+        while cur < upper_bound {
+            if pfa.page_frame_table[PageFrame(cur)].state == PageFrameState::Free {
+                let run_start = cur;
+                while cur < upper_bound && pfa.page_frame_table[PageFrame(cur)].state == PageFrameState::Free {
+                    cur += 1;
+                }
+                pfa.insert_range(run_start, cur);
+            } else {
+                cur += 1;
+            }
+        }
+
+        pfa
+    }
+
+    pub fn page_frame_table(&self) -> &PageFrameTable {
+        &self.page_frame_table
+    }
+
+    /// Mutable access to the page frame table, meant for changing the owners and share counts
+    /// of allocated frames. Changing the state of a frame breaks the allocator.
+    pub fn page_frame_table_mut(&mut self) -> &mut PageFrameTable {
+        &mut self.page_frame_table
+    }
+
+    /// Number of page frames that are currently free.
+    pub fn free_count(&self) -> usize {
+        self.free_counts.iter().sum()
+    }
+
+    /// Number of page frames that are currently free in the given zone.
+    pub fn zone_free_count(&self, zone: Zone) -> usize {
+        self.free_counts[zone.index()]
+    }
+
+    /// Allocate a naturally aligned block of `2^order` page frames from the given zone only.
+    /// The frames are not yet marked as allocated.
+    fn alloc_block(&mut self, order: usize, zone: Zone) -> Option<usize> {
+        let free_lists = &self.free_lists[zone.index()];
+        let mut current_order = (order..ORDER_COUNT).find(|o| free_lists[*o] != PageFrameInfo::NIL)?;
+        let block = free_lists[current_order] as usize;
+        self.remove_block(block, current_order);
+        // split the block until it has the requested size, returning the upper halves
+        while current_order > order {
+            current_order -= 1;
+            self.push_block(block + (1 << current_order), current_order);
+        }
+        self.free_counts[zone.index()] -= 1 << order;
+        Some(block)
+    }
+
+    /// Allocate an aligned region of page frames from the given zone only.
+    fn alloc_region_from(&mut self, page_count: usize, alignment: usize, zone: Zone) -> Option<usize> {
+        // blocks are naturally aligned, so requesting a larger block takes care of the alignment
+        let order = page_count.max(alignment).next_power_of_two().trailing_zeros() as usize;
+        if order <= MAX_ORDER {
+            let block = self.alloc_block(order, zone)?;
+            // return the unused tail of the block
+            self.insert_range(block + page_count, block + (1 << order));
+            Some(block)
+        } else {
+            let region = self.page_frame_table.zone_region(zone);
+            let start = self.page_frame_table.find_free_run(page_count, alignment, region)?.0;
+            self.claim_range(start, start + page_count);
+            Some(start)
+        }
+    }
+
+    /// Free a naturally aligned block of `2^order` page frames whose frames are already
+    /// marked as free, merging it with its buddies.
+    fn free_block(&mut self, mut block: usize, mut order: usize) {
+        self.free_counts[Zone::containing(PageFrame(block)).index()] += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = block ^ (1 << order);
+            if ! self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove_block(buddy, order);
+            block &= buddy;
+            order += 1;
+        }
+        self.push_block(block, order);
+    }
+
+    /// Add a range of free page frames to the free lists without attempting to merge
+    /// the resulting blocks with their buddies.
+    fn insert_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let order = largest_block_order(start, end);
+            self.push_block(start, order);
+            self.free_counts[Zone::containing(PageFrame(start)).index()] += 1 << order;
+            start += 1 << order;
+        }
+    }
+
+    /// Free a range of page frames whose frames are already marked as free.
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let order = largest_block_order(start, end);
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    /// Remove all free blocks overlapping the given range from the free lists,
+    /// returning the parts outside of the range to the free lists.
+    fn claim_range(&mut self, start: usize, end: usize) {
+        let mut cur = start;
+        while cur < end {
+            let (block, order) = self.containing_free_block(cur)
+                .expect("claiming frame that is not free");
+            self.remove_block(block, order);
+            self.free_counts[Zone::containing(PageFrame(block)).index()] -= 1 << order;
+            let block_end = block + (1 << order);
+            self.insert_range(block, start.max(block));
+            self.insert_range(end.min(block_end), block_end);
+            cur = block_end;
+        }
+    }
+
+    /// Find the free block that contains the given frame.
+    fn containing_free_block(&self, frame: usize) -> Option<(usize, usize)> {
+        (0..ORDER_COUNT)
+            .map(|order| (frame & !((1 << order) - 1), order))
+            .find(|&(block, order)| self.is_free_block(block, order))
+    }
+
+    fn is_free_block(&self, block: usize, order: usize) -> bool {
+        if block >= self.page_frame_table.upper_bound().0 {
+            return false;
+        }
+        let info = &self.page_frame_table[PageFrame(block)];
+        info.state == PageFrameState::Free && info.order as usize == order
+    }
+
+    fn push_block(&mut self, block: usize, order: usize) {
+        let free_lists = &mut self.free_lists[Zone::containing(PageFrame(block)).index()];
+        let head = free_lists[order];
+        free_lists[order] = block as u32;
+        {
+            let info = &mut self.page_frame_table[PageFrame(block)];
+            info.order = order as u8;
+            info.prev = PageFrameInfo::NIL;
+            info.next = head;
+        }
+        if head != PageFrameInfo::NIL {
+            self.page_frame_table[PageFrame(head as usize)].prev = block as u32;
+        }
+    }
+
+    fn remove_block(&mut self, block: usize, order: usize) {
+        let (prev, next) = {
+            let info = &mut self.page_frame_table[PageFrame(block)];
+            let links = (info.prev, info.next);
+            info.order = PageFrameInfo::NO_ORDER;
+            info.prev = PageFrameInfo::NIL;
+            info.next = PageFrameInfo::NIL;
+            links
+        };
+        if prev == PageFrameInfo::NIL {
+            self.free_lists[Zone::containing(PageFrame(block)).index()][order] = next;
+        } else {
+            self.page_frame_table[PageFrame(prev as usize)].next = next;
+        }
+        if next != PageFrameInfo::NIL {
+            self.page_frame_table[PageFrame(next as usize)].prev = prev;
+        }
+    }
+
+    fn mark_allocated(&mut self, start: usize, end: usize) {
+        for frame in PageFrame(start) .. PageFrame(end) {
+            let entry = &mut self.page_frame_table[frame];
+            assert_eq!(entry.state, PageFrameState::Free);
+            entry.set_allocated();
+        }
+    }
+
+    fn mark_free(&mut self, start: usize, end: usize) {
+        for frame in PageFrame(start) .. PageFrame(end) {
+            self.page_frame_table[frame].set_free();
+        }
+    }
+}
+
+impl PageFrameAllocator for BuddyPageFrameAllocator {
+    unsafe fn alloc_in(&mut self, zone: Zone) -> Option<PageFrame> {
+        let frame = zone.fallbacks().filter_map(|z| self.alloc_block(0, z)).next()?;
+        self.mark_allocated(frame, frame + 1);
+        Some(PageFrame(frame))
+    }
+
+    unsafe fn free(&mut self, frame: PageFrame) {
+        self.mark_free(frame.0, frame.0 + 1);
+        self.free_block(frame.0, 0);
+    }
+
+    unsafe fn alloc_aligned_region_in(&mut self, page_count: usize, alignment: usize, zone: Zone) -> Option<PageFrameRegion> {
+        assert!(alignment.is_power_of_two(), "alignment must be power of two");
+        if page_count == 0 {
+            return None;
+        }
+        let start = zone.fallbacks().filter_map(|z| self.alloc_region_from(page_count, alignment, z)).next()?;
+        self.mark_allocated(start, start + page_count);
+        Some(PageFrameRegion {
+            start: PageFrame(start),
+            end: PageFrame(start + page_count),
+        })
+    }
+
+    unsafe fn free_region(&mut self, region: PageFrameRegion) {
+        self.mark_free(region.start.0, region.end.0);
+        self.free_range(region.start.0, region.end.0);
+    }
+
+    unsafe fn cache(&mut self, frame: PageFrame) {
+        self.page_frame_table.mark_cached(frame)
+    }
+
+    unsafe fn uncache(&mut self, frame: PageFrame) {
+        self.page_frame_table.take_cached(frame)
+    }
+
+    fn set_owner(&mut self, region: PageFrameRegion, owner: PageFrameOwner) {
+        self.page_frame_table.set_owner(region, owner)
+    }
+}
+
+/// Order of the largest naturally aligned block starting at `start` that fits before `end`.
+fn largest_block_order(start: usize, end: usize) -> usize {
+    let align_order = if start == 0 { MAX_ORDER } else { start.trailing_zeros() as usize };
+    let size_order = (mem::size_of::<usize>() * 8 - 1) - (end - start).leading_zeros() as usize;
+    align_order.min(size_order).min(MAX_ORDER)
+}
+
+#[cfg(test)]
+mod test {
+    use amd64::VirtAddr;
+    use crate::physical::alloc::LARGE_PAGE_FRAMES;
+    use crate::physical::mgmt::PageFrameOwner;
+    use super::*;
+
+    fn page_frame_table(storage: &mut Vec<PageFrameInfo>, frame_count: usize) -> PageFrameTable {
+        storage.reserve_exact(frame_count);
+        unsafe { PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), frame_count) }
+    }
+
+    #[test]
+    fn test_buddy_split_and_merge() {
+        let mut storage = Vec::new();
+        let pft = page_frame_table(&mut storage, 4096);
+        let mut pfa = BuddyPageFrameAllocator::new(pft);
+        assert_eq!(pfa.free_count(), 4096);
+
+        unsafe {
+            let frames: Vec<PageFrame> = (0..4096).map(|_| pfa.alloc().unwrap()).collect();
+            assert_eq!(pfa.free_count(), 0);
+            assert!(pfa.alloc().is_none());
+            for f in frames {
+                pfa.free(f);
+            }
+        }
+        assert_eq!(pfa.free_count(), 4096);
+        // everything must have been merged back into blocks of the largest order
+        for order in 0..MAX_ORDER {
+            assert_eq!(pfa.free_lists[Zone::Dma.index()][order], PageFrameInfo::NIL, "order {}", order);
+        }
+        assert_ne!(pfa.free_lists[Zone::Dma.index()][MAX_ORDER], PageFrameInfo::NIL);
+    }
+
+    #[test]
+    fn test_buddy_regions() {
+        let mut storage = Vec::new();
+        let mut pft = page_frame_table(&mut storage, 3000);
+        pft.mark_reserved(PageFrameRegion { start: PageFrame(0), end: PageFrame(3) });
+        pft.mark_allocated(PageFrameRegion { start: PageFrame(1500), end: PageFrame(1501) }, PageFrameOwner::Kernel);
+        let mut pfa = BuddyPageFrameAllocator::new(pft);
+        assert_eq!(pfa.free_count(), 2996);
+
+        unsafe {
+            let a = pfa.alloc_region(5).unwrap();
+            assert_eq!(a.length(), 5);
+            assert!(a.start.0 >= 3);
+            assert_eq!(pfa.free_count(), 2991);
+
+            // larger than the largest block, must not cross the allocated frame
+            let b = pfa.alloc_region(1400).unwrap();
+            assert_eq!(b.length(), 1400);
+            assert!(b.end.0 <= 1500 || b.start.0 > 1500, "b = {:?}", b);
+            assert!(pfa.alloc_region(1600).is_none());
+
+            for frame in a.start .. a.end {
+                assert_eq!(pfa.page_frame_table()[frame].state, PageFrameState::Allocated);
+            }
+
+            pfa.free_region(a);
+            pfa.free_region(b);
+        }
+        assert_eq!(pfa.free_count(), 2996);
+        assert_eq!(pfa.page_frame_table().stats().allocated_count, 1);
+    }
+
+    #[test]
+    fn test_buddy_zone_fallback() {
+        let mut storage = Vec::new();
+        let dma32_start = Zone::Dma32.start().0;
+        let pft = page_frame_table(&mut storage, dma32_start + 512);
+        let mut pfa = BuddyPageFrameAllocator::new(pft);
+        assert_eq!(pfa.zone_free_count(Zone::Dma), dma32_start);
+        assert_eq!(pfa.zone_free_count(Zone::Dma32), 512);
+
+        unsafe {
+            // the upper zone is preferred
+            let r = pfa.alloc_region(512).unwrap();
+            assert_eq!(r.start.0, dma32_start);
+            // then allocations fall back to lower zones
+            let f = pfa.alloc().unwrap();
+            assert_eq!(Zone::containing(f), Zone::Dma);
+            // but never go to higher zones
+            pfa.free_region(r);
+            let d = pfa.alloc_region_in(16, Zone::Dma).unwrap();
+            assert_eq!(Zone::containing(d.end - 1), Zone::Dma);
+            assert!(pfa.alloc_region_in(dma32_start, Zone::Dma).is_none());
+        }
+    }
+
+    #[test]
+    fn test_buddy_aligned_regions() {
+        let mut storage = Vec::new();
+        let mut pft = page_frame_table(&mut storage, 8192);
+        pft.mark_reserved(PageFrameRegion { start: PageFrame(0), end: PageFrame(1) });
+        let mut pfa = BuddyPageFrameAllocator::new(pft);
+
+        unsafe {
+            let small = pfa.alloc_region(3).unwrap();
+            let large = pfa.alloc_large_page().unwrap();
+            assert_eq!(large.length(), LARGE_PAGE_FRAMES);
+            assert_eq!(large.start.0 % LARGE_PAGE_FRAMES, 0);
+
+            // alignment larger than the largest block
+            let huge = pfa.alloc_aligned_region(1500, 4096).unwrap();
+            assert_eq!(huge.start.0, 4096);
+            assert!(pfa.alloc_aligned_region(1500, 4096).is_none());
+
+            pfa.free_region(huge);
+            pfa.free_region(large);
+            pfa.free_region(small);
+        }
+        assert_eq!(pfa.free_count(), 8191);
+    }
+}
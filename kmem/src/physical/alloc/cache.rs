@@ -0,0 +1,253 @@
+//! Per-CPU caches of single page frames in front of a global page frame allocator.
+//!
+//! Each CPU owns a `FrameCache` holding a small stack of free page frames. Single frame
+//! allocations and frees are served from that stack, and only when it runs empty or full,
+//! the global allocator has to refill or drain a whole batch of frames.
+//!
+//! Cached frames are marked as such in the page frame table, so that frees through the cache are
+//! checked like those going to the global allocator, and frames start out without an owner when
+//! they leave the cache. That still requires locking the global allocator, but only briefly.
+//!
+//! Zone specific allocations and region allocations always go to the global allocator.
+
+use crate::physical::{PageFrame, PageFrameRegion};
+use crate::physical::alloc::PageFrameAllocator;
+use crate::physical::mgmt::PageFrameOwner;
+use crate::physical::zone::Zone;
+
+/// Maximum number of frames held by a single cache.
+pub const FRAME_CACHE_CAPACITY: usize = 32;
+
+/// Number of frames transferred between a cache and the global allocator at once.
+pub const FRAME_CACHE_BATCH: usize = FRAME_CACHE_CAPACITY / 2;
+
+/// Counters describing how well a cache performs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameCacheStats {
+    /// Number of allocations served from the cache.
+    pub hits: usize,
+    /// Number of allocations that required a refill from the global allocator.
+    pub misses: usize,
+    /// Number of times frames were returned to the global allocator because the cache was full.
+    pub drains: usize,
+}
+
+/// A cache of free page frames belonging to a single CPU.
+pub struct FrameCache {
+    frames: [PageFrame; FRAME_CACHE_CAPACITY],
+    count: usize,
+    stats: FrameCacheStats,
+}
+
+impl FrameCache {
+    pub const fn new() -> FrameCache {
+        FrameCache {
+            frames: [PageFrame(0); FRAME_CACHE_CAPACITY],
+            count: 0,
+            stats: FrameCacheStats {
+                hits: 0,
+                misses: 0,
+                drains: 0,
+            },
+        }
+    }
+
+    /// Number of frames currently held by the cache.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn stats(&self) -> FrameCacheStats {
+        self.stats
+    }
+
+    /// Allocate a single frame, refilling the cache from the global allocator if it is empty.
+    pub unsafe fn alloc<A: PageFrameAllocator + ?Sized>(&mut self, global: &spin::Mutex<A>) -> Option<PageFrame> {
+        let mut global = global.lock();
+        if self.count > 0 {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            while self.count < FRAME_CACHE_BATCH {
+                match global.alloc() {
+                    Some(frame) => {
+                        global.cache(frame);
+                        self.push(frame);
+                    },
+                    None => break,
+                }
+            }
+        }
+        let frame = self.pop()?;
+        global.uncache(frame);
+        Some(frame)
+    }
+
+    /// Free a single frame, draining part of the cache to the global allocator if it is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the frame is not allocated or still shared.
+    pub unsafe fn free<A: PageFrameAllocator + ?Sized>(&mut self, frame: PageFrame, global: &spin::Mutex<A>) {
+        let mut global = global.lock();
+        global.cache(frame);
+        if self.count == FRAME_CACHE_CAPACITY {
+            self.stats.drains += 1;
+            while self.count > FRAME_CACHE_CAPACITY - FRAME_CACHE_BATCH {
+                let frame = self.pop().unwrap();
+                global.uncache(frame);
+                global.free(frame);
+            }
+        }
+        self.push(frame);
+    }
+
+    /// Return all cached frames to the global allocator.
+    pub unsafe fn drain<A: PageFrameAllocator + ?Sized>(&mut self, global: &spin::Mutex<A>) {
+        let mut global = global.lock();
+        while let Some(frame) = self.pop() {
+            global.uncache(frame);
+            global.free(frame);
+        }
+    }
+
+    fn push(&mut self, frame: PageFrame) {
+        self.frames[self.count] = frame;
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<PageFrame> {
+        if self.count == 0 {
+            None
+        } else {
+            self.count -= 1;
+            Some(self.frames[self.count])
+        }
+    }
+}
+
+/// A page frame allocator serving single frames from a CPU local cache,
+/// and everything else from the global allocator.
+pub struct CachedPageFrameAllocator<'a, A: PageFrameAllocator + ?Sized + 'a> {
+    cache: &'a mut FrameCache,
+    global: &'a spin::Mutex<A>,
+}
+
+impl<'a, A: PageFrameAllocator + ?Sized> CachedPageFrameAllocator<'a, A> {
+    pub fn new(cache: &'a mut FrameCache, global: &'a spin::Mutex<A>) -> Self {
+        CachedPageFrameAllocator {
+            cache: cache,
+            global: global,
+        }
+    }
+}
+
+impl<'a, A: PageFrameAllocator + ?Sized> PageFrameAllocator for CachedPageFrameAllocator<'a, A> {
+    unsafe fn alloc_in(&mut self, zone: Zone) -> Option<PageFrame> {
+        // the cache may hold frames of any zone, so it can only serve unrestricted requests
+        if zone == Zone::Normal {
+            self.cache.alloc(self.global)
+        } else {
+            self.global.lock().alloc_in(zone)
+        }
+    }
+
+    unsafe fn free(&mut self, frame: PageFrame) {
+        self.cache.free(frame, self.global)
+    }
+
+    unsafe fn alloc_aligned_region_in(&mut self, page_count: usize, alignment: usize, zone: Zone) -> Option<PageFrameRegion> {
+        self.global.lock().alloc_aligned_region_in(page_count, alignment, zone)
+    }
+
+    unsafe fn free_region(&mut self, region: PageFrameRegion) {
+        self.global.lock().free_region(region)
+    }
+
+    unsafe fn cache(&mut self, frame: PageFrame) {
+        self.global.lock().cache(frame)
+    }
+
+    unsafe fn uncache(&mut self, frame: PageFrame) {
+        self.global.lock().uncache(frame)
+    }
+
+    fn set_owner(&mut self, region: PageFrameRegion, owner: PageFrameOwner) {
+        self.global.lock().set_owner(region, owner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amd64::VirtAddr;
+    use crate::physical::alloc::BuddyPageFrameAllocator;
+    use crate::physical::mgmt::{PageFrameTable, PageFrameInfo, PageFrameState};
+    use super::*;
+
+    #[test]
+    fn test_frame_cache_refill_and_drain() {
+        let mut storage: Vec<PageFrameInfo> = Vec::with_capacity(1024);
+        let pft = unsafe { PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), 1024) };
+        let global = spin::Mutex::new(BuddyPageFrameAllocator::new(pft));
+        let mut cache = FrameCache::new();
+
+        unsafe {
+            // every batch of allocations causes one miss
+            let frames: Vec<PageFrame> = (0..2 * FRAME_CACHE_BATCH + 1).map(|_| cache.alloc(&global).unwrap()).collect();
+            assert_eq!(cache.stats(), FrameCacheStats { hits: 2 * FRAME_CACHE_BATCH - 2, misses: 3, drains: 0 });
+            assert_eq!(cache.len(), FRAME_CACHE_BATCH - 1);
+            assert_eq!(global.lock().free_count(), 1024 - 3 * FRAME_CACHE_BATCH);
+            let stats = global.lock().page_frame_table().stats();
+            assert_eq!((stats.allocated_count, stats.cached_count), (2 * FRAME_CACHE_BATCH + 1, FRAME_CACHE_BATCH - 1));
+
+            for frame in frames {
+                cache.free(frame, &global);
+            }
+            assert_eq!(cache.stats().drains, 1);
+            assert_eq!(cache.len(), FRAME_CACHE_CAPACITY);
+
+            cache.drain(&global);
+            assert!(cache.is_empty());
+            assert_eq!(global.lock().free_count(), 1024);
+        }
+    }
+
+    #[test]
+    fn test_cached_frames_are_reset() {
+        let mut storage: Vec<PageFrameInfo> = Vec::with_capacity(64);
+        let pft = unsafe { PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), 64) };
+        let global = spin::Mutex::new(BuddyPageFrameAllocator::new(pft));
+        let mut cache = FrameCache::new();
+        let mut pfa = CachedPageFrameAllocator::new(&mut cache, &global);
+
+        unsafe {
+            let frame = pfa.alloc().unwrap();
+            pfa.set_frame_owner(frame, PageFrameOwner::KernelHeap);
+            pfa.free(frame);
+            assert_eq!(global.lock().page_frame_table()[frame].state, PageFrameState::Cached);
+            // the most recently freed frame is handed out first
+            assert_eq!(pfa.alloc(), Some(frame));
+            assert_eq!(global.lock().page_frame_table()[frame].owner, PageFrameOwner::Unknown);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_cached_double_free() {
+        let mut storage: Vec<PageFrameInfo> = Vec::with_capacity(64);
+        let pft = unsafe { PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), 64) };
+        let global = spin::Mutex::new(BuddyPageFrameAllocator::new(pft));
+        let mut cache = FrameCache::new();
+        let mut pfa = CachedPageFrameAllocator::new(&mut cache, &global);
+
+        unsafe {
+            let frame = pfa.alloc().unwrap();
+            pfa.free(frame);
+            pfa.free(frame);
+        }
+    }
+}
@@ -1,17 +1,75 @@
 use crate::physical::{PageFrame, PageFrameRegion};
+use crate::physical::mgmt::PageFrameOwner;
+use crate::physical::zone::Zone;
+use crate::{LARGE_PAGE_SIZE, PAGE_SIZE};
 
+mod buddy;
+mod cache;
 mod slow;
 
+pub use self::buddy::BuddyPageFrameAllocator;
+pub use self::cache::{FrameCache, FrameCacheStats, CachedPageFrameAllocator};
 pub use self::slow::SlowPageFrameAllocator;
 
+/// Number of page frames making up a large page.
+pub const LARGE_PAGE_FRAMES: usize = LARGE_PAGE_SIZE / PAGE_SIZE;
+
 /// Generic interface for a page frame allocator.
 pub trait PageFrameAllocator {
-    unsafe fn alloc(&mut self) -> Option<PageFrame>;
-    /// Free a single page frame previously allocated via `alloc`.
+    /// Allocate a single page frame from the given zone.
+    /// When the zone is exhausted, the lower zones are tried in turn.
+    unsafe fn alloc_in(&mut self, zone: Zone) -> Option<PageFrame>;
+
+    /// Allocate a single page frame from any zone, preferring the highest one.
+    unsafe fn alloc(&mut self) -> Option<PageFrame> {
+        self.alloc_in(Zone::Normal)
+    }
+
+    /// Free a single page frame previously allocated via `alloc` or `alloc_in`.
     unsafe fn free(&mut self, frame: PageFrame);
 
-    /// Allocate a consecutive region of physical page frames.
-    unsafe fn alloc_region(&mut self, page_count: usize) -> Option<PageFrameRegion>;
-    /// Free a consecutive region of physical page frames previously allocated via `alloc_region`.
+    /// Allocate a consecutive region of physical page frames that lies completely within the given zone,
+    /// and whose first frame is a multiple of `alignment` frames, which must be a power of two.
+    /// When the zone cannot satisfy the request, the lower zones are tried in turn.
+    unsafe fn alloc_aligned_region_in(&mut self, page_count: usize, alignment: usize, zone: Zone) -> Option<PageFrameRegion>;
+
+    /// Allocate an aligned consecutive region of physical page frames from any zone, preferring the highest one.
+    unsafe fn alloc_aligned_region(&mut self, page_count: usize, alignment: usize) -> Option<PageFrameRegion> {
+        self.alloc_aligned_region_in(page_count, alignment, Zone::Normal)
+    }
+
+    /// Allocate a consecutive region of physical page frames that lies completely within the given zone.
+    /// When the zone cannot satisfy the request, the lower zones are tried in turn.
+    unsafe fn alloc_region_in(&mut self, page_count: usize, zone: Zone) -> Option<PageFrameRegion> {
+        self.alloc_aligned_region_in(page_count, 1, zone)
+    }
+
+    /// Allocate a consecutive region of physical page frames from any zone, preferring the highest one.
+    unsafe fn alloc_region(&mut self, page_count: usize) -> Option<PageFrameRegion> {
+        self.alloc_region_in(page_count, Zone::Normal)
+    }
+
+    /// Allocate the frames backing a single large page, i.e. a 2 MiB aligned region of 2 MiB.
+    unsafe fn alloc_large_page(&mut self) -> Option<PageFrameRegion> {
+        self.alloc_aligned_region(LARGE_PAGE_FRAMES, LARGE_PAGE_FRAMES)
+    }
+
+    /// Free a consecutive region of physical page frames previously allocated via one of the
+    /// region allocation functions.
     unsafe fn free_region(&mut self, region: PageFrameRegion);
+
+    /// Mark a frame allocated via `alloc` as held by a page frame cache. The frame is checked and
+    /// reset like by `free`, but it is not handed out again until taken back via `uncache`.
+    unsafe fn cache(&mut self, frame: PageFrame);
+
+    /// Mark a frame held by a page frame cache as freshly allocated again.
+    unsafe fn uncache(&mut self, frame: PageFrame);
+
+    /// Record what the frames of an allocated region are used for, see `PageFrameTable::set_owner`.
+    fn set_owner(&mut self, region: PageFrameRegion, owner: PageFrameOwner);
+
+    /// Record what a single allocated frame is used for.
+    fn set_frame_owner(&mut self, frame: PageFrame, owner: PageFrameOwner) {
+        self.set_owner(PageFrameRegion { start: frame, end: frame + 1 }, owner)
+    }
 }
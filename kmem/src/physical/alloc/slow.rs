@@ -2,7 +2,8 @@
 
 use crate::physical::{PageFrame, PageFrameRegion};
 use crate::physical::alloc::PageFrameAllocator;
-use crate::physical::mgmt::{PageFrameTable, PageFrameState};
+use crate::physical::mgmt::{PageFrameTable, PageFrameState, PageFrameOwner};
+use crate::physical::zone::Zone;
 
 pub struct SlowPageFrameAllocator {
     page_frame_table: PageFrameTable,
@@ -22,59 +23,70 @@ impl SlowPageFrameAllocator {
     pub fn page_frame_table_mut(&mut self) -> &mut PageFrameTable {
         &mut self.page_frame_table
     }
+
+    /// Give up the allocator, e.g. for handing the table over to a faster one.
+    pub fn into_page_frame_table(self) -> PageFrameTable {
+        self.page_frame_table
+    }
 }
 
 impl PageFrameAllocator for SlowPageFrameAllocator {
-    unsafe fn alloc(&mut self) -> Option<PageFrame> {
-        // search first free page
-        for frame in PageFrame(0) .. self.page_frame_table.upper_bound() {
-            let entry = &mut self.page_frame_table[frame];
-            if entry.state == PageFrameState::Free {
-                entry.state = PageFrameState::Allocated;
-                return Some(frame)
+    unsafe fn alloc_in(&mut self, zone: Zone) -> Option<PageFrame> {
+        // search first free page, starting in the highest acceptable zone
+        for zone in zone.fallbacks() {
+            let region = self.page_frame_table.zone_region(zone);
+            for frame in region.start .. region.end {
+                let entry = &mut self.page_frame_table[frame];
+                if entry.state == PageFrameState::Free {
+                    entry.set_allocated();
+                    return Some(frame)
+                }
             }
         }
         return None
     }
 
     unsafe fn free(&mut self, frame: PageFrame) {
-        let entry = &mut self.page_frame_table[frame];
-        assert_eq!(entry.state, PageFrameState::Allocated);
-        entry.state = PageFrameState::Free;
+        self.page_frame_table[frame].set_free();
     }
 
     
-    unsafe fn alloc_region(&mut self, page_count: usize) -> Option<PageFrameRegion> {
-        // search first free region of that size
-        let mut cur = PageFrame(0);
-        let mut free_count = 0;
-        while cur < self.page_frame_table.upper_bound() && free_count < page_count {
-            if self.page_frame_table[cur].state == PageFrameState::Free {
-                free_count += 1;
-            } else {
-                free_count = 0;
-            }
-            cur += 1;
+    unsafe fn alloc_aligned_region_in(&mut self, page_count: usize, alignment: usize, zone: Zone) -> Option<PageFrameRegion> {
+        assert!(alignment.is_power_of_two(), "alignment must be power of two");
+        if page_count == 0 {
+            return None;
         }
-        if free_count == page_count {
-            for i in cur - free_count .. cur {
-                self.page_frame_table[i].state = PageFrameState::Allocated;
+        for zone in zone.fallbacks() {
+            // search first free region of that size
+            let region = self.page_frame_table.zone_region(zone);
+            if let Some(start) = self.page_frame_table.find_free_run(page_count, alignment, region) {
+                for i in start .. start + page_count {
+                    self.page_frame_table[i].set_allocated();
+                }
+                return Some(PageFrameRegion {
+                    start: start,
+                    end: start + page_count
+                })
             }
-            Some(PageFrameRegion {
-                start: cur - page_count,
-                end: cur
-            })
-        } else {
-            None
         }
+        None
     }
     
     unsafe fn free_region(&mut self, region: PageFrameRegion) {
         for frame in region.start .. region.end {
-            let entry = &mut self.page_frame_table[frame];
-            assert_eq!(entry.state, PageFrameState::Allocated);
-            entry.state = PageFrameState::Free;
+            self.page_frame_table[frame].set_free();
         }
     }
 
+    unsafe fn cache(&mut self, frame: PageFrame) {
+        self.page_frame_table.mark_cached(frame)
+    }
+
+    unsafe fn uncache(&mut self, frame: PageFrame) {
+        self.page_frame_table.take_cached(frame)
+    }
+
+    fn set_owner(&mut self, region: PageFrameRegion, owner: PageFrameOwner) {
+        self.page_frame_table.set_owner(region, owner)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,117 @@
+//! A simple allocator for the early boot phase, before the page frame table exists.
+//!
+//! It is set up from the available RAM regions of the memory map, minus the regions that are
+//! already in use (kernel, modules, boot loader data, ...), and hands out frames from the lowest
+//! free region that is large enough. Once the page frame table has been created, all carved out
+//! and allocated regions are transferred to it, and the allocator is no longer used.
+
+use amd64::Alignable;
+
+use crate::physical::{PageFrame, PageFrameRegion};
+use crate::physical::mgmt::{PageFrameTable, PageFrameOwner};
+use crate::physical::regions::RegionSet;
+
+/// Maximum number of regions that can be carved out of the available memory.
+pub const BOOTMEM_MAX_CARVE_OUTS: usize = 32;
+
+pub struct BootMemAllocator {
+    /// Frames that can still be allocated.
+    free: RegionSet,
+    /// Regions that were already in use before the allocator was created, together with their owners.
+    carved_out: [(PageFrameRegion, PageFrameOwner); BOOTMEM_MAX_CARVE_OUTS],
+    carved_out_count: usize,
+    /// Frames that have been allocated.
+    used: RegionSet,
+}
+
+impl BootMemAllocator {
+    pub const fn new() -> BootMemAllocator {
+        BootMemAllocator {
+            free: RegionSet::new(),
+            carved_out: [(PageFrameRegion { start: PageFrame(0), end: PageFrame(0) }, PageFrameOwner::Unknown); BOOTMEM_MAX_CARVE_OUTS],
+            carved_out_count: 0,
+            used: RegionSet::new(),
+        }
+    }
+
+    /// Add a region of available memory.
+    pub fn add_available(&mut self, region: PageFrameRegion) {
+        self.free.insert(region);
+    }
+
+    /// Remove a region that is already in use from the available memory.
+    /// It is marked as allocated for the given owner when handing over to the page frame table.
+    pub fn carve_out(&mut self, region: PageFrameRegion, owner: PageFrameOwner) {
+        if region.is_empty() {
+            return;
+        }
+        assert!(self.carved_out_count < BOOTMEM_MAX_CARVE_OUTS, "too many boot memory carve outs");
+        self.free.remove(region);
+        self.carved_out[self.carved_out_count] = (region, owner);
+        self.carved_out_count += 1;
+    }
+
+    /// Allocate `page_count` consecutive frames, with the first frame being a multiple of `alignment`,
+    /// from the lowest free region large enough.
+    pub fn alloc(&mut self, page_count: usize, alignment: usize) -> Option<PageFrameRegion> {
+        let start = self.free.iter()
+            .map(|r| r.split_at(PageFrame(r.start.0.align_up(alignment))).1)
+            .find(|r| r.length() >= page_count)?
+            .start;
+        let region = PageFrameRegion { start: start, end: start + page_count };
+        self.free.remove(region);
+        self.used.insert(region);
+        Some(region)
+    }
+
+    /// The frames that are still free.
+    pub fn free_regions(&self) -> &RegionSet {
+        &self.free
+    }
+
+    /// The frames that have been allocated.
+    pub fn used_regions(&self) -> &RegionSet {
+        &self.used
+    }
+
+    /// Hand over to the page frame table by marking all carved out and allocated regions as allocated.
+    /// The remaining free frames stay free in the table.
+    pub fn finish(self, page_frame_table: &mut PageFrameTable) {
+        for &(region, owner) in self.carved_out[0..self.carved_out_count].iter() {
+            page_frame_table.mark_allocated(region, owner);
+        }
+        for region in self.used.iter() {
+            page_frame_table.mark_allocated(region, PageFrameOwner::Kernel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn region(start: usize, end: usize) -> PageFrameRegion {
+        PageFrameRegion { start: PageFrame(start), end: PageFrame(end) }
+    }
+
+    #[test]
+    fn test_bootmem_alloc() {
+        let mut bootmem = BootMemAllocator::new();
+        bootmem.add_available(region(0, 160));
+        bootmem.add_available(region(256, 1024));
+        bootmem.add_available(region(100, 200));
+        assert_eq!(bootmem.free_regions().regions(), &[region(0, 200), region(256, 1024)]);
+
+        bootmem.carve_out(region(1, 2), PageFrameOwner::Kernel);
+        bootmem.carve_out(region(150, 400), PageFrameOwner::Module);
+        assert_eq!(bootmem.free_regions().regions(), &[region(0, 1), region(2, 150), region(400, 1024)]);
+
+        assert_eq!(bootmem.alloc(4, 1), Some(region(2, 6)));
+        assert_eq!(bootmem.alloc(4, 16), Some(region(16, 20)));
+        assert_eq!(bootmem.alloc(200, 1), Some(region(400, 600)));
+        assert_eq!(bootmem.alloc(1000, 1), None);
+        assert_eq!(bootmem.free_regions().regions(),
+            &[region(0, 1), region(6, 16), region(20, 150), region(600, 1024)]);
+        assert_eq!(bootmem.used_regions().regions(), &[region(2, 6), region(16, 20), region(400, 600)]);
+    }
+}
@@ -0,0 +1,88 @@
+//! Simple pattern tests for finding defective RAM before it is handed out.
+//!
+//! Every tested frame is filled with a couple of fixed bit patterns and with its own addresses,
+//! and read back afterwards. Frames where any word does not read back as written are marked as
+//! defective in the page frame table. The contents of tested frames are destroyed.
+
+use amd64::VirtAddr;
+use core::ptr;
+
+use crate::physical::{PageFrame, PageFrameRegion};
+use crate::physical::mgmt::{PageFrameTable, PageFrameState};
+use crate::paging::direct::DirectMapping;
+use crate::PAGE_SIZE;
+
+/// Fixed patterns written to every word of the tested memory.
+const PATTERNS: [u64; 4] = [
+    0x0000_0000_0000_0000,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xAAAA_AAAA_AAAA_AAAA,
+    0x5555_5555_5555_5555,
+];
+
+/// Test the memory of `length` bytes at `start` (a multiple of eight bytes), returning whether it is intact.
+pub unsafe fn test_memory(start: VirtAddr, length: usize) -> bool {
+    let words = start.0 as *mut u64;
+    let count = length / 8;
+
+    for &pattern in PATTERNS.iter() {
+        for i in 0..count {
+            ptr::write_volatile(words.add(i), pattern);
+        }
+        for i in 0..count {
+            if ptr::read_volatile(words.add(i)) != pattern {
+                return false;
+            }
+        }
+    }
+
+    // catch address lines that are stuck or shorted by writing each word's own address
+    for i in 0..count {
+        ptr::write_volatile(words.add(i), words.add(i) as u64);
+    }
+    for i in 0..count {
+        if ptr::read_volatile(words.add(i)) != words.add(i) as u64 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Test all free frames of the table that are accessible through the direct mapping,
+/// and mark the failing ones as defective. Returns the number of defective frames found.
+pub unsafe fn test_free_frames(page_frame_table: &mut PageFrameTable, mapping: &DirectMapping) -> usize {
+    let mut defective = 0;
+    for frame in PageFrame(0) .. page_frame_table.upper_bound() {
+        if page_frame_table[frame].state != PageFrameState::Free || ! mapping.contains_phys(frame.start_address()) {
+            continue;
+        }
+        if ! test_memory(mapping.phys_to_virt(frame.start_address()), PAGE_SIZE) {
+            warn!("[memtest] frame {:p} is defective", frame.start_address());
+            page_frame_table.mark_defective(PageFrameRegion { start: frame, end: frame + 1 });
+            defective += 1;
+        }
+    }
+    defective
+}
+
+#[cfg(test)]
+mod test {
+    use amd64::PhysAddr;
+    use crate::physical::mgmt::{PageFrameInfo, PageFrameOwner};
+    use super::*;
+
+    #[test]
+    fn test_free_frames_only() {
+        let mut memory: Vec<u64> = vec![0x1234; 4 * PAGE_SIZE / 8];
+        let mapping = DirectMapping::new(VirtAddr(memory.as_mut_ptr() as usize), PhysAddr(0), 4 * PAGE_SIZE);
+        let mut storage: Vec<PageFrameInfo> = Vec::with_capacity(4);
+        let mut pft = unsafe { PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), 4) };
+        pft.mark_allocated(PageFrameRegion { start: PageFrame(1), end: PageFrame(2) }, PageFrameOwner::Kernel);
+
+        assert_eq!(unsafe { test_free_frames(&mut pft, &mapping) }, 0);
+        assert_eq!(pft.stats().defective_count, 0);
+        // the allocated frame was left alone, the others were overwritten
+        assert!(memory[PAGE_SIZE / 8 .. 2 * PAGE_SIZE / 8].iter().all(|&w| w == 0x1234));
+        assert!(memory[0 .. PAGE_SIZE / 8].iter().all(|&w| w != 0x1234));
+    }
+}
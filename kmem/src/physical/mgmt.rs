@@ -1,17 +1,22 @@
 ///! Functionality for managing physical memory pages.
 
 use crate::physical::{PageFrame, PageFrameRegion};
+use crate::physical::zone::Zone;
 
+use core::cmp;
 use core::mem;
 use core::ops::{Index, IndexMut};
 
-use amd64::VirtAddr;
+use amd64::{Alignable, VirtAddr};
 
 pub struct PageFrameTable {
     ptr: *mut PageFrameInfo,
     length: usize,
 }
 
+/// The table exclusively owns the memory it points to, so it can be moved to another thread.
+unsafe impl Send for PageFrameTable {}
+
 impl PageFrameTable {
     /// Required number of bytes for holding a page frame table for at most
     /// `num_page_frames` page frames.
@@ -24,9 +29,7 @@ impl PageFrameTable {
     pub unsafe fn from_addr(addr: VirtAddr, num_page_frames: usize) -> PageFrameTable {
         let ptr: *mut PageFrameInfo = addr.as_mut_ptr();
         for i in 0..num_page_frames {
-            ptr.add(i).write(PageFrameInfo {
-                state: PageFrameState::Free,
-            });
+            ptr.add(i).write(PageFrameInfo::new(PageFrameState::Free));
         }
         PageFrameTable {
             ptr: ptr,
@@ -34,11 +37,13 @@ impl PageFrameTable {
         }
     }
 
-    /// Marks a whole region as reserved
-    pub fn mark_allocated(&mut self, region: PageFrameRegion) {
+    /// Marks a whole region as allocated for the given owner.
+    pub fn mark_allocated(&mut self, region: PageFrameRegion, owner: PageFrameOwner) {
         for entry in self.region_iter_mut(region) {
             assert!(entry.state != PageFrameState::Reserved, "cannot allocate reserved region");
-            entry.state = PageFrameState::Allocated;
+            assert!(entry.state != PageFrameState::Defective, "cannot allocate defective region");
+            entry.set_allocated();
+            entry.owner = owner;
         }
     }
 
@@ -46,32 +51,136 @@ impl PageFrameTable {
     pub fn mark_reserved(&mut self, region: PageFrameRegion) {
         for entry in self.region_iter_mut(region) {
             assert!(entry.state != PageFrameState::Allocated, "cannot reserve allocated region");
-            entry.state = PageFrameState::Reserved;
+            if entry.state != PageFrameState::Defective {
+                entry.state = PageFrameState::Reserved;
+            }
         }
     }
 
+    /// Marks a whole region as defective, so that it is never handed out again.
+    pub fn mark_defective(&mut self, region: PageFrameRegion) {
+        for entry in self.region_iter_mut(region) {
+            assert!(entry.state != PageFrameState::Allocated, "cannot quarantine allocated region");
+            entry.state = PageFrameState::Defective;
+        }
+    }
+
+    /// Record what the frames of an allocated region are used for.
+    pub fn set_owner(&mut self, region: PageFrameRegion, owner: PageFrameOwner) {
+        for entry in self.region_iter_mut(region) {
+            assert_eq!(entry.state, PageFrameState::Allocated, "cannot set owner of unallocated frame");
+            entry.owner = owner;
+        }
+    }
+
+    /// Hand an allocated frame over to a page frame cache, which holds it until it is allocated again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the frame is not allocated or still shared, just like freeing it would.
+    pub fn mark_cached(&mut self, frame: PageFrame) {
+        self[frame].set_cached();
+    }
+
+    /// Mark a frame held by a page frame cache as freshly allocated.
+    pub fn take_cached(&mut self, frame: PageFrame) {
+        let entry = &mut self[frame];
+        assert_eq!(entry.state, PageFrameState::Cached, "frame is not cached");
+        entry.set_allocated();
+    }
+
+    /// Add another reference to an allocated frame, returning the new share count.
+    pub fn share(&mut self, frame: PageFrame) -> u16 {
+        let entry = &mut self[frame];
+        assert_eq!(entry.state, PageFrameState::Allocated, "cannot share unallocated frame");
+        entry.share_count = entry.share_count.checked_add(1).expect("share count overflow");
+        entry.share_count
+    }
+
+    /// Drop a reference to an allocated frame, returning the remaining share count.
+    /// The last reference must be dropped by freeing the frame via its allocator,
+    /// once this function returns one.
+    pub fn unshare(&mut self, frame: PageFrame) -> u16 {
+        let entry = &mut self[frame];
+        assert_eq!(entry.state, PageFrameState::Allocated, "cannot unshare unallocated frame");
+        assert!(entry.share_count > 1, "cannot unshare last reference");
+        entry.share_count -= 1;
+        entry.share_count
+    }
+
     pub fn upper_bound(&self) -> PageFrame {
         PageFrame(self.length)
     }
 
+    /// The frames of the given zone that are covered by this table.
+    /// The region is empty if the zone lies completely above the last frame.
+    pub fn zone_region(&self, zone: Zone) -> PageFrameRegion {
+        let frames = zone.frames();
+        PageFrameRegion {
+            start: cmp::min(frames.start, self.upper_bound()),
+            end: cmp::min(frames.end, self.upper_bound()),
+        }
+    }
+
     fn region_iter_mut<'a>(&'a mut self, region: PageFrameRegion) -> impl Iterator<Item=&'a mut PageFrameInfo> {
         assert!(region.start.0 < self.length && region.end.0 <= self.length);
         (region.start.0 .. region.end.0).into_iter().map(move |i| unsafe { &mut *self.ptr.add(i) } )
     }
 
     pub fn stats(&self) -> PageFrameStats {
-        let (mut alloced, mut reserved) = (0, 0);
-        for frame in PageFrame(0)..self.upper_bound() {
-            match self.index(frame).state {
-                PageFrameState::Allocated => alloced += 1,
+        self.region_stats(PageFrameRegion { start: PageFrame(0), end: self.upper_bound() })
+    }
+
+    /// Find the first run of `page_count` free frames inside `region` whose first frame
+    /// is a multiple of `alignment` frames, by scanning the table.
+    pub fn find_free_run(&self, page_count: usize, alignment: usize, region: PageFrameRegion) -> Option<PageFrame> {
+        let mut run_start = region.start.0.align_up(alignment);
+        let mut cur = run_start;
+        while cur < region.end.0 {
+            if self[PageFrame(cur)].state != PageFrameState::Free {
+                run_start = (cur + 1).align_up(alignment);
+                cur = run_start;
+            } else if cur + 1 - run_start == page_count {
+                return Some(PageFrame(run_start));
+            } else {
+                cur += 1;
+            }
+        }
+        None
+    }
+
+    /// Statistics about the frames belonging to a single zone.
+    pub fn zone_stats(&self, zone: Zone) -> PageFrameStats {
+        self.region_stats(self.zone_region(zone))
+    }
+
+    fn region_stats(&self, region: PageFrameRegion) -> PageFrameStats {
+        let (mut alloced, mut cached, mut reserved, mut defective, mut shared) = (0, 0, 0, 0, 0);
+        let mut owners = [0; PageFrameOwner::COUNT];
+        for frame in region.start .. region.end {
+            let entry = self.index(frame);
+            match entry.state {
+                PageFrameState::Allocated => {
+                    alloced += 1;
+                    owners[entry.owner as usize] += 1;
+                    if entry.share_count > 1 {
+                        shared += 1;
+                    }
+                },
+                PageFrameState::Cached => cached += 1,
                 PageFrameState::Reserved => reserved += 1,
+                PageFrameState::Defective => defective += 1,
                 PageFrameState::Free => {},
             }
         }
         PageFrameStats {
-            total_count: self.length,
+            total_count: region.length(),
             reserved_count: reserved,
+            defective_count: defective,
             allocated_count: alloced,
+            cached_count: cached,
+            shared_count: shared,
+            owner_counts: owners,
         }
     }
 }
@@ -97,7 +206,22 @@ impl IndexMut<PageFrame> for PageFrameTable {
 pub struct PageFrameStats {
     pub total_count: usize,
     pub reserved_count: usize,
-    pub allocated_count: usize
+    /// Number of frames that are known to be broken.
+    pub defective_count: usize,
+    pub allocated_count: usize,
+    /// Number of free frames held by page frame caches.
+    pub cached_count: usize,
+    /// Number of allocated frames that are referenced more than once.
+    pub shared_count: usize,
+    /// Number of allocated frames per owner, indexed by `PageFrameOwner`.
+    pub owner_counts: [usize; PageFrameOwner::COUNT],
+}
+
+impl PageFrameStats {
+    /// Number of allocated frames belonging to the given owner.
+    pub fn owner_count(&self, owner: PageFrameOwner) -> usize {
+        self.owner_counts[owner as usize]
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -105,8 +229,161 @@ pub enum PageFrameState {
     Free = 0,
     Allocated = 1,
     Reserved = 2,
+    /// The frame is known to be broken and is never handed out.
+    Defective = 3,
+    /// The frame is free, but held by a page frame cache rather than the allocator.
+    Cached = 4,
+}
+
+/// What an allocated page frame is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFrameOwner {
+    /// Not (yet) known, frames returned by the allocators start out this way.
+    Unknown = 0,
+    /// The kernel binary and data structures set up during boot.
+    Kernel = 1,
+    KernelHeap = 2,
+    PageTable = 3,
+    User = 4,
+    /// Modules loaded by the bootloader.
+    Module = 5,
+    Dma = 6,
+}
+
+impl PageFrameOwner {
+    /// Number of different owners.
+    pub const COUNT: usize = 7;
+
+    pub const ALL: [PageFrameOwner; PageFrameOwner::COUNT] = [
+        PageFrameOwner::Unknown,
+        PageFrameOwner::Kernel,
+        PageFrameOwner::KernelHeap,
+        PageFrameOwner::PageTable,
+        PageFrameOwner::User,
+        PageFrameOwner::Module,
+        PageFrameOwner::Dma,
+    ];
 }
 
 pub struct PageFrameInfo {
-    pub state: PageFrameState
-}
\ No newline at end of file
+    pub state: PageFrameState,
+    /// What the frame is used for, only meaningful for allocated frames.
+    pub owner: PageFrameOwner,
+    /// Number of references to an allocated frame, e.g. from multiple page tables.
+    pub share_count: u16,
+    /// Order of the free block starting at this frame, or `NO_ORDER` if no free block starts here.
+    /// Only maintained by the buddy allocator.
+    pub(crate) order: u8,
+    /// Previous free block of the same order (buddy allocator free list).
+    pub(crate) prev: u32,
+    /// Next free block of the same order (buddy allocator free list).
+    pub(crate) next: u32,
+}
+
+impl PageFrameInfo {
+    /// Marker for frames that do not start a free block.
+    pub(crate) const NO_ORDER: u8 = 0xFF;
+    /// Marker for the end of a free list.
+    pub(crate) const NIL: u32 = 0xFFFF_FFFF;
+
+    pub fn new(state: PageFrameState) -> PageFrameInfo {
+        PageFrameInfo {
+            state: state,
+            owner: PageFrameOwner::Unknown,
+            share_count: 0,
+            order: Self::NO_ORDER,
+            prev: Self::NIL,
+            next: Self::NIL,
+        }
+    }
+
+    /// Mark a frame as freshly allocated with a single reference.
+    pub(crate) fn set_allocated(&mut self) {
+        self.state = PageFrameState::Allocated;
+        self.owner = PageFrameOwner::Unknown;
+        self.share_count = 1;
+    }
+
+    /// Mark an allocated frame as free.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the frame is not allocated or still shared.
+    pub(crate) fn set_free(&mut self) {
+        self.release(PageFrameState::Free);
+    }
+
+    /// Mark an allocated frame as held by a page frame cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the frame is not allocated or still shared.
+    pub(crate) fn set_cached(&mut self) {
+        self.release(PageFrameState::Cached);
+    }
+
+    fn release(&mut self, state: PageFrameState) {
+        assert_eq!(self.state, PageFrameState::Allocated, "cannot free unallocated frame");
+        assert!(self.share_count <= 1, "cannot free shared frame");
+        self.state = state;
+        self.owner = PageFrameOwner::Unknown;
+        self.share_count = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_share_counts_and_owners() {
+        let mut storage: Vec<PageFrameInfo> = Vec::with_capacity(16);
+        let mut pft = unsafe { PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), 16) };
+        pft.mark_reserved(PageFrameRegion { start: PageFrame(0), end: PageFrame(2) });
+        pft.mark_allocated(PageFrameRegion { start: PageFrame(2), end: PageFrame(6) }, PageFrameOwner::Kernel);
+        pft.mark_allocated(PageFrameRegion { start: PageFrame(6), end: PageFrame(7) }, PageFrameOwner::Unknown);
+        pft.set_owner(PageFrameRegion { start: PageFrame(6), end: PageFrame(7) }, PageFrameOwner::User);
+
+        assert_eq!(pft[PageFrame(6)].share_count, 1);
+        assert_eq!(pft.share(PageFrame(6)), 2);
+        assert_eq!(pft.share(PageFrame(6)), 3);
+
+        let stats = pft.stats();
+        assert_eq!(stats.reserved_count, 2);
+        assert_eq!(stats.allocated_count, 5);
+        assert_eq!(stats.shared_count, 1);
+        assert_eq!(stats.owner_count(PageFrameOwner::Kernel), 4);
+        assert_eq!(stats.owner_count(PageFrameOwner::User), 1);
+        assert_eq!(stats.owner_count(PageFrameOwner::Dma), 0);
+
+        assert_eq!(pft.unshare(PageFrame(6)), 2);
+        assert_eq!(pft.unshare(PageFrame(6)), 1);
+        pft[PageFrame(6)].set_free();
+        assert_eq!(pft.stats().allocated_count, 4);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_free_shared_frame() {
+        let mut storage: Vec<PageFrameInfo> = Vec::with_capacity(4);
+        let mut pft = unsafe { PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), 4) };
+        pft.mark_allocated(PageFrameRegion { start: PageFrame(1), end: PageFrame(2) }, PageFrameOwner::User);
+        pft.share(PageFrame(1));
+        pft[PageFrame(1)].set_free();
+    }
+
+    #[test]
+    fn test_defective_frames() {
+        let mut storage: Vec<PageFrameInfo> = Vec::with_capacity(8);
+        let mut pft = unsafe { PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), 8) };
+        pft.mark_defective(PageFrameRegion { start: PageFrame(2), end: PageFrame(4) });
+        // reserving does not lift the quarantine
+        pft.mark_reserved(PageFrameRegion { start: PageFrame(0), end: PageFrame(3) });
+        assert_eq!(pft[PageFrame(2)].state, PageFrameState::Defective);
+
+        let stats = pft.stats();
+        assert_eq!(stats.defective_count, 2);
+        assert_eq!(stats.reserved_count, 2);
+        assert_eq!(pft.find_free_run(4, 1, PageFrameRegion { start: PageFrame(0), end: PageFrame(8) }), Some(PageFrame(4)));
+    }
+}
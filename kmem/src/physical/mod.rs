@@ -1,10 +1,15 @@
 use amd64::{Alignable, PhysAddr};
+use core::cmp;
 use core::mem;
 use core::ops;
 use crate::{PAGE_SIZE, PAGE_ALIGN_BITS};
 
 pub mod alloc;
+pub mod bootmem;
+pub mod memtest;
 pub mod mgmt;
+pub mod regions;
+pub mod zone;
 
 /// Number of a physical page frame, counted from the start.
 /// The first page frame at physical address 0x0 has number zero.
@@ -86,7 +91,7 @@ impl core::iter::Step for PageFrame {
 }
 
 /// A region of physical page frames.
-#[derive(Eq, PartialEq, Debug, Clone)]
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
 pub struct PageFrameRegion {
     /// The first frame included in the region.
     pub start: PageFrame,
@@ -123,13 +128,56 @@ impl PageFrameRegion {
     pub fn is_empty(&self) -> bool {
         self.start >= self.end
     }
+
+    /// Whether the given frame is part of the region.
+    pub fn contains(&self, frame: PageFrame) -> bool {
+        self.start <= frame && frame < self.end
+    }
+
+    /// Whether the two regions have at least one frame in common.
+    pub fn overlaps(&self, other: &PageFrameRegion) -> bool {
+        self.intersection(other).is_some()
+    }
+
+    /// The frames contained in both regions, if there are any.
+    pub fn intersection(&self, other: &PageFrameRegion) -> Option<PageFrameRegion> {
+        let region = PageFrameRegion {
+            start: cmp::max(self.start, other.start),
+            end: cmp::min(self.end, other.end),
+        };
+        if region.is_empty() { None } else { Some(region) }
+    }
+
+    /// The frames of this region that are not contained in `other`.
+    /// These are the (possibly missing) parts below and above `other`.
+    pub fn subtract(&self, other: &PageFrameRegion) -> (Option<PageFrameRegion>, Option<PageFrameRegion>) {
+        if ! self.overlaps(other) {
+            return if self.is_empty() { (None, None) } else { (Some(*self), None) };
+        }
+        let below = PageFrameRegion { start: self.start, end: other.start };
+        let above = PageFrameRegion { start: other.end, end: self.end };
+        (
+            if below.is_empty() { None } else { Some(below) },
+            if above.is_empty() { None } else { Some(above) },
+        )
+    }
+
+    /// Split the region into the frames below `frame` and the frames starting at `frame`.
+    /// If the frame lies outside the region, one of the parts is empty.
+    pub fn split_at(&self, frame: PageFrame) -> (PageFrameRegion, PageFrameRegion) {
+        let middle = cmp::min(cmp::max(frame, self.start), self.end);
+        (
+            PageFrameRegion { start: self.start, end: middle },
+            PageFrameRegion { start: middle, end: self.end },
+        )
+    }
 }
 
 
 #[cfg(test)]
 mod test {
     use amd64::{PhysAddr};
-    use super::PageFrameRegion;
+    use super::{PageFrame, PageFrameRegion};
 
     #[test]
     fn test_page_frame_region() {
@@ -159,4 +207,31 @@ mod test {
         assert!(!whole_mem.is_empty());
         assert_eq!(whole_mem.length(), 0x0010_0000_0000_0000)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_page_frame_region_algebra() {
+        let region = |start, end| PageFrameRegion { start: PageFrame(start), end: PageFrame(end) };
+        let a = region(10, 20);
+
+        assert!(a.contains(PageFrame(10)) && a.contains(PageFrame(19)));
+        assert!(!a.contains(PageFrame(9)) && !a.contains(PageFrame(20)));
+
+        assert!(a.overlaps(&region(19, 30)));
+        assert!(!a.overlaps(&region(20, 30)));
+        assert!(!a.overlaps(&region(15, 15)));
+
+        assert_eq!(a.intersection(&region(5, 15)), Some(region(10, 15)));
+        assert_eq!(a.intersection(&region(12, 14)), Some(region(12, 14)));
+        assert_eq!(a.intersection(&region(0, 10)), None);
+
+        assert_eq!(a.subtract(&region(12, 14)), (Some(region(10, 12)), Some(region(14, 20))));
+        assert_eq!(a.subtract(&region(0, 15)), (None, Some(region(15, 20))));
+        assert_eq!(a.subtract(&region(15, 30)), (Some(region(10, 15)), None));
+        assert_eq!(a.subtract(&region(0, 30)), (None, None));
+        assert_eq!(a.subtract(&region(30, 40)), (Some(a), None));
+
+        assert_eq!(a.split_at(PageFrame(13)), (region(10, 13), region(13, 20)));
+        assert_eq!(a.split_at(PageFrame(5)), (region(10, 10), region(10, 20)));
+        assert_eq!(a.split_at(PageFrame(25)), (region(10, 20), region(20, 20)));
+    }
+}
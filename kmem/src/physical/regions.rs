@@ -0,0 +1,200 @@
+//! A set of page frames, stored as a sorted list of non-overlapping regions.
+//!
+//! The set has a fixed capacity, so that it can be used before any allocator is available,
+//! e.g. for computing the initial memory layout from the memory map.
+
+use core::cmp;
+
+use crate::physical::{PageFrame, PageFrameRegion};
+
+/// Maximum number of disjoint regions a `RegionSet` can hold.
+pub const REGION_SET_CAPACITY: usize = 32;
+
+/// A set of page frames, represented by sorted, non-overlapping and non-adjacent regions.
+#[derive(Clone)]
+pub struct RegionSet {
+    regions: [PageFrameRegion; REGION_SET_CAPACITY],
+    count: usize,
+}
+
+impl RegionSet {
+    pub const fn new() -> RegionSet {
+        RegionSet {
+            regions: [PageFrameRegion { start: PageFrame(0), end: PageFrame(0) }; REGION_SET_CAPACITY],
+            count: 0,
+        }
+    }
+
+    /// Number of disjoint regions in the set.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The regions in the set, in ascending order.
+    pub fn regions(&self) -> &[PageFrameRegion] {
+        &self.regions[0..self.count]
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=PageFrameRegion> + 'a {
+        self.regions().iter().cloned()
+    }
+
+    /// Total number of frames in the set.
+    pub fn frame_count(&self) -> usize {
+        self.iter().map(|r| r.length()).sum()
+    }
+
+    /// Whether the frame is part of the set.
+    pub fn contains(&self, frame: PageFrame) -> bool {
+        self.iter().any(|r| r.contains(frame))
+    }
+
+    /// Whether any frame of the region is part of the set.
+    pub fn overlaps(&self, region: &PageFrameRegion) -> bool {
+        self.iter().any(|r| r.overlaps(region))
+    }
+
+    /// Add all frames of the region to the set, merging it with overlapping and adjacent regions.
+    ///
+    /// # Panics
+    ///
+    /// Panics when the set cannot hold the additional region.
+    pub fn insert(&mut self, region: PageFrameRegion) {
+        if region.is_empty() {
+            return;
+        }
+        // regions touching the new one are merged with it
+        let first = self.regions().iter().position(|r| r.end >= region.start).unwrap_or(self.count);
+        let last = self.regions()[first..].iter().position(|r| r.start > region.end).map_or(self.count, |i| first + i);
+        let merged = if first < last {
+            PageFrameRegion {
+                start: cmp::min(region.start, self.regions[first].start),
+                end: cmp::max(region.end, self.regions[last - 1].end),
+            }
+        } else {
+            region
+        };
+        self.splice(first, last, &[merged]);
+    }
+
+    /// Remove all frames of the region from the set.
+    ///
+    /// # Panics
+    ///
+    /// Panics when removing the region splits an existing one and the set is full.
+    pub fn remove(&mut self, region: PageFrameRegion) {
+        if region.is_empty() {
+            return;
+        }
+        let first = self.regions().iter().position(|r| r.end > region.start).unwrap_or(self.count);
+        let last = self.regions()[first..].iter().position(|r| r.start >= region.end).map_or(self.count, |i| first + i);
+        if first == last {
+            return;
+        }
+        let (below, _) = self.regions[first].subtract(&region);
+        let (_, above) = self.regions[last - 1].subtract(&region);
+        let mut remaining = [region; 2];
+        let mut remaining_count = 0;
+        for part in below.into_iter().chain(above.into_iter()) {
+            remaining[remaining_count] = part;
+            remaining_count += 1;
+        }
+        self.splice(first, last, &remaining[0..remaining_count]);
+    }
+
+    /// Remove all frames outside of the given bounds from the set.
+    pub fn retain_within(&mut self, bounds: PageFrameRegion) {
+        self.remove(PageFrameRegion { start: PageFrame(0), end: bounds.start });
+        self.remove(PageFrameRegion { start: bounds.end, end: PageFrame(usize::max_value()) });
+    }
+
+    /// Replace the regions at indices `first..last` by the given ones.
+    fn splice(&mut self, first: usize, last: usize, replacement: &[PageFrameRegion]) {
+        let new_count = self.count - (last - first) + replacement.len();
+        assert!(new_count <= REGION_SET_CAPACITY, "region set capacity exceeded");
+        let new_last = first + replacement.len();
+        if new_last > last {
+            for i in (last..self.count).rev() {
+                self.regions[i + new_last - last] = self.regions[i];
+            }
+        } else {
+            for i in last..self.count {
+                self.regions[i + new_last - last] = self.regions[i];
+            }
+        }
+        self.regions[first..new_last].copy_from_slice(replacement);
+        self.count = new_count;
+    }
+}
+
+impl core::fmt::Debug for RegionSet {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_list().entries(self.regions().iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn region(start: usize, end: usize) -> PageFrameRegion {
+        PageFrameRegion { start: PageFrame(start), end: PageFrame(end) }
+    }
+
+    #[test]
+    fn test_region_set_insert() {
+        let mut set = RegionSet::new();
+        set.insert(region(10, 20));
+        set.insert(region(30, 40));
+        set.insert(region(0, 5));
+        set.insert(region(50, 50));
+        assert_eq!(set.regions(), &[region(0, 5), region(10, 20), region(30, 40)]);
+
+        // adjacent regions are merged
+        set.insert(region(20, 25));
+        assert_eq!(set.regions(), &[region(0, 5), region(10, 25), region(30, 40)]);
+
+        // overlapping several regions
+        set.insert(region(3, 32));
+        assert_eq!(set.regions(), &[region(0, 40)]);
+        assert_eq!(set.frame_count(), 40);
+    }
+
+    #[test]
+    fn test_region_set_remove() {
+        let mut set = RegionSet::new();
+        set.insert(region(0, 100));
+        set.insert(region(200, 300));
+
+        set.remove(region(10, 20));
+        assert_eq!(set.regions(), &[region(0, 10), region(20, 100), region(200, 300)]);
+
+        set.remove(region(50, 250));
+        assert_eq!(set.regions(), &[region(0, 10), region(20, 50), region(250, 300)]);
+
+        set.remove(region(0, 10));
+        set.remove(region(100, 200));
+        assert_eq!(set.regions(), &[region(20, 50), region(250, 300)]);
+
+        set.retain_within(region(30, 260));
+        assert_eq!(set.regions(), &[region(30, 50), region(250, 260)]);
+
+        assert!(set.contains(PageFrame(30)));
+        assert!(!set.contains(PageFrame(50)));
+        assert!(set.overlaps(&region(0, 31)));
+        assert!(!set.overlaps(&region(50, 250)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_region_set_capacity() {
+        let mut set = RegionSet::new();
+        for i in 0..=REGION_SET_CAPACITY {
+            set.insert(region(2 * i, 2 * i + 1));
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Zones of physical memory that are distinguished by which devices are able to access them.
+//!
+//! Allocations name the highest zone they are willing to accept. When that zone is exhausted,
+//! the allocators fall back to the lower zones, so that the scarce low memory is only used
+//! when either explicitly requested or when nothing else is left.
+
+use crate::physical::{PageFrame, PageFrameRegion};
+use crate::PAGE_ALIGN_BITS;
+
+/// A zone of physical memory.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Copy, Clone)]
+pub enum Zone {
+    /// Memory below 16 MiB, reachable by ISA DMA.
+    Dma = 0,
+    /// Memory below 4 GiB, reachable by devices that only use 32 bit addresses.
+    Dma32 = 1,
+    /// All remaining memory.
+    Normal = 2,
+}
+
+impl Zone {
+    /// Number of zones.
+    pub const COUNT: usize = 3;
+
+    /// All zones, from lowest to highest.
+    pub const ALL: [Zone; Zone::COUNT] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// Physical address where the DMA32 zone starts.
+    const DMA32_START: usize = 16 << 20;
+
+    /// Physical address where the normal zone starts.
+    const NORMAL_START: usize = 4 << 30;
+
+    /// The index of the zone, counting from the lowest one.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The first frame belonging to the zone.
+    pub fn start(self) -> PageFrame {
+        match self {
+            Zone::Dma => PageFrame(0),
+            Zone::Dma32 => PageFrame(Self::DMA32_START >> PAGE_ALIGN_BITS),
+            Zone::Normal => PageFrame(Self::NORMAL_START >> PAGE_ALIGN_BITS),
+        }
+    }
+
+    /// The first frame after the zone (not included).
+    pub fn end(self) -> PageFrame {
+        match self {
+            Zone::Dma => Zone::Dma32.start(),
+            Zone::Dma32 => Zone::Normal.start(),
+            Zone::Normal => PageFrame(usize::max_value() >> PAGE_ALIGN_BITS),
+        }
+    }
+
+    /// All page frames that could belong to the zone.
+    pub fn frames(self) -> PageFrameRegion {
+        PageFrameRegion {
+            start: self.start(),
+            end: self.end(),
+        }
+    }
+
+    /// The zone containing the given page frame.
+    pub fn containing(frame: PageFrame) -> Zone {
+        if frame < Zone::Dma32.start() {
+            Zone::Dma
+        } else if frame < Zone::Normal.start() {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    /// The highest zone whose frames all lie below the given frame, if any.
+    /// This is useful for translating the addressing limit of a device into a zone.
+    pub fn below(limit: PageFrame) -> Option<Zone> {
+        Zone::ALL.iter().rev().cloned().find(|z| z.end() <= limit)
+    }
+
+    /// Iterate over the zones that can be used for satisfying an allocation
+    /// in this zone, in the order they should be tried.
+    pub fn fallbacks(self) -> impl Iterator<Item=Zone> {
+        Zone::ALL[0..=self.index()].iter().rev().cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_zone_boundaries() {
+        assert_eq!(Zone::containing(PageFrame(0)), Zone::Dma);
+        assert_eq!(Zone::containing(PageFrame(0xFFF)), Zone::Dma);
+        assert_eq!(Zone::containing(PageFrame(0x1000)), Zone::Dma32);
+        assert_eq!(Zone::containing(PageFrame(0xF_FFFF)), Zone::Dma32);
+        assert_eq!(Zone::containing(PageFrame(0x10_0000)), Zone::Normal);
+
+        assert_eq!(Zone::below(PageFrame(0x800)), None);
+        assert_eq!(Zone::below(PageFrame(0x1000)), Some(Zone::Dma));
+        assert_eq!(Zone::below(PageFrame(0x10_0000)), Some(Zone::Dma32));
+
+        let order: Vec<Zone> = Zone::Normal.fallbacks().collect();
+        assert_eq!(order, vec![Zone::Normal, Zone::Dma32, Zone::Dma]);
+        let order: Vec<Zone> = Zone::Dma.fallbacks().collect();
+        assert_eq!(order, vec![Zone::Dma]);
+    }
+}
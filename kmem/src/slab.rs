@@ -0,0 +1,418 @@
+//! Caches of same-sized objects, carved from whole page frames.
+//!
+//! Each slab is a single page frame accessed through the direct mapping. The objects fill the frame
+//! from its start, followed by a header and a table linking the free objects of the slab. Since the
+//! links are kept out of the objects themselves, a freed object retains the state it was constructed
+//! with, and the constructor of a cache only runs once per object when its slab is created. The
+//! links of allocated objects hold a marker instead, so that freeing an object twice is detected.
+//!
+//! Slabs are kept in three lists: full, partially used and empty. Allocations are served from the
+//! partially used slabs first, so that empty slabs can be given back to the page frame allocator
+//! via `shrink` when memory runs short.
+
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr::{self, NonNull};
+
+use amd64::{Alignable, VirtAddr};
+
+use crate::PAGE_SIZE;
+use crate::paging::direct::DirectMapping;
+use crate::physical::PageFrame;
+use crate::physical::alloc::PageFrameAllocator;
+use crate::physical::mgmt::PageFrameOwner;
+
+/// Marks the end of the free list of a slab.
+const NO_OBJECT: u16 = 0xFFFF;
+
+/// Stored in the link of an allocated object, for catching double frees.
+const ALLOCATED: u16 = 0xFFFE;
+
+/// Bookkeeping at the end of every slab, followed by the free list links.
+struct SlabHeader {
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    /// Index of the first free object.
+    free: u16,
+    /// Number of allocated objects.
+    in_use: u16,
+}
+
+/// Counters describing the state of a cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SlabCacheStats {
+    /// Number of slabs, i.e. page frames, held by the cache.
+    pub slabs: usize,
+    /// Number of slabs without any allocated objects.
+    pub empty_slabs: usize,
+    /// Number of objects currently allocated.
+    pub objects_in_use: usize,
+    /// Total number of allocations served by the cache.
+    pub allocations: usize,
+    /// Number of slabs that were created.
+    pub grows: usize,
+    /// Number of empty slabs that were given back to the page frame allocator.
+    pub shrinks: usize,
+}
+
+/// The parts of a cache that do not depend on the object type,
+/// e.g. for reclaiming memory from all caches of the system at once.
+pub trait ObjectCache {
+    fn name(&self) -> &'static str;
+
+    fn stats(&self) -> SlabCacheStats;
+
+    /// Give all empty slabs back to the page frame allocator, returning the number of freed frames.
+    unsafe fn shrink(&mut self, pfa: &mut PageFrameAllocator) -> usize;
+}
+
+/// A cache handing out objects of type `T`.
+///
+/// The cache never gives memory back on its own, empty slabs are only freed by `shrink`.
+/// Slabs that are still held when the cache is dropped are leaked.
+pub struct SlabCache<T> {
+    name: &'static str,
+    /// Used for accessing the page frames of the slabs.
+    mapping: &'static DirectMapping,
+    constructor: Option<fn() -> T>,
+    full: *mut SlabHeader,
+    partial: *mut SlabHeader,
+    empty: *mut SlabHeader,
+    stats: SlabCacheStats,
+    _objects: PhantomData<T>,
+}
+
+// The cache exclusively owns the slabs and the objects that are not allocated.
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Create a cache whose objects are handed out uninitialized.
+    pub const fn new(name: &'static str, mapping: &'static DirectMapping) -> SlabCache<T> {
+        SlabCache {
+            name: name,
+            mapping: mapping,
+            constructor: None,
+            full: ptr::null_mut(),
+            partial: ptr::null_mut(),
+            empty: ptr::null_mut(),
+            stats: SlabCacheStats {
+                slabs: 0,
+                empty_slabs: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                grows: 0,
+                shrinks: 0,
+            },
+            _objects: PhantomData,
+        }
+    }
+
+    /// Create a cache whose objects are initialized by `constructor` when their slab is created.
+    /// Objects must be returned to the cache in their constructed state.
+    pub const fn with_constructor(name: &'static str, mapping: &'static DirectMapping, constructor: fn() -> T) -> SlabCache<T> {
+        SlabCache {
+            constructor: Some(constructor),
+            ..SlabCache::new(name, mapping)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> SlabCacheStats {
+        self.stats
+    }
+
+    /// The space taken by a single object within a slab.
+    pub fn object_size() -> usize {
+        mem::size_of::<T>().max(1).align_up(mem::align_of::<T>())
+    }
+
+    /// Number of objects stored in a single slab.
+    pub fn objects_per_slab() -> usize {
+        let mut count = (PAGE_SIZE - mem::size_of::<SlabHeader>()) / (Self::object_size() + mem::size_of::<u16>());
+        while count > 0 && Self::links_offset(count) + count * mem::size_of::<u16>() > PAGE_SIZE {
+            count -= 1;
+        }
+        count
+    }
+
+    /// Allocate an object, creating a new slab if all slabs are full.
+    /// Returns `None` if no page frame was available for the new slab.
+    ///
+    /// Without a constructor, the object is uninitialized.
+    pub unsafe fn alloc(&mut self, pfa: &mut PageFrameAllocator) -> Option<NonNull<T>> {
+        if self.partial.is_null() {
+            let slab = if self.empty.is_null() {
+                self.grow(pfa)?
+            } else {
+                let slab = self.empty;
+                unlink(&mut self.empty, slab);
+                self.stats.empty_slabs -= 1;
+                slab
+            };
+            push(&mut self.partial, slab);
+        }
+
+        let slab = self.partial;
+        let index = (*slab).free;
+        (*slab).free = *links(slab).add(index as usize);
+        *links(slab).add(index as usize) = ALLOCATED;
+        (*slab).in_use += 1;
+        if (*slab).free == NO_OBJECT {
+            unlink(&mut self.partial, slab);
+            push(&mut self.full, slab);
+        }
+        self.stats.objects_in_use += 1;
+        self.stats.allocations += 1;
+        Some(NonNull::new_unchecked(self.object(slab, index)))
+    }
+
+    /// Return an object allocated from this cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the address does not refer to an allocated object of a slab, e.g. when it is freed twice.
+    pub unsafe fn free(&mut self, object: NonNull<T>) {
+        let addr = object.as_ptr() as usize;
+        let base = addr.align_down(PAGE_SIZE);
+        let offset = addr - base;
+        let index = offset / Self::object_size();
+        assert!(offset % Self::object_size() == 0 && index < Self::objects_per_slab(),
+            "{:p} is not an object of cache {}", object, self.name);
+        let slab = (base + Self::header_offset(Self::objects_per_slab())) as *mut SlabHeader;
+        assert!((*slab).in_use > 0, "{:p} is not an object of cache {}", object, self.name);
+        assert!(*links(slab).add(index) == ALLOCATED, "double free of {:p} in cache {}", object, self.name);
+
+        let was_full = (*slab).free == NO_OBJECT;
+        *links(slab).add(index) = (*slab).free;
+        (*slab).free = index as u16;
+        (*slab).in_use -= 1;
+        if was_full {
+            unlink(&mut self.full, slab);
+            push(&mut self.partial, slab);
+        }
+        if (*slab).in_use == 0 {
+            unlink(&mut self.partial, slab);
+            push(&mut self.empty, slab);
+            self.stats.empty_slabs += 1;
+        }
+        self.stats.objects_in_use -= 1;
+    }
+
+    /// Give all empty slabs back to the page frame allocator, returning the number of freed frames.
+    /// Constructed objects are dropped before their slab is freed.
+    pub unsafe fn shrink(&mut self, pfa: &mut PageFrameAllocator) -> usize {
+        let mut count = 0;
+        while ! self.empty.is_null() {
+            let slab = self.empty;
+            unlink(&mut self.empty, slab);
+            if self.constructor.is_some() {
+                for i in 0..Self::objects_per_slab() {
+                    ptr::drop_in_place(self.object(slab, i as u16));
+                }
+            }
+            let base = VirtAddr(slab as usize).align_down(PAGE_SIZE);
+            pfa.free(PageFrame::including(self.mapping.virt_to_phys(base)));
+            count += 1;
+        }
+        self.stats.slabs -= count;
+        self.stats.empty_slabs -= count;
+        self.stats.shrinks += count;
+        count
+    }
+
+    /// Turn a fresh page frame into an empty slab.
+    unsafe fn grow(&mut self, pfa: &mut PageFrameAllocator) -> Option<*mut SlabHeader> {
+        let count = Self::objects_per_slab();
+        assert!(count > 0, "objects of cache {} do not fit into a slab", self.name);
+        let frame = pfa.alloc()?;
+        pfa.set_frame_owner(frame, PageFrameOwner::KernelHeap);
+        let base = self.mapping.phys_to_virt(frame.start_address());
+        let slab: *mut SlabHeader = (base + Self::header_offset(count)).as_mut_ptr();
+        slab.write(SlabHeader {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free: 0,
+            in_use: 0,
+        });
+        for i in 0..count {
+            let next = if i + 1 < count { (i + 1) as u16 } else { NO_OBJECT };
+            links(slab).add(i).write(next);
+        }
+        if let Some(constructor) = self.constructor {
+            for i in 0..count {
+                self.object(slab, i as u16).write(constructor());
+            }
+        }
+        self.stats.slabs += 1;
+        self.stats.grows += 1;
+        Some(slab)
+    }
+
+    /// The object with the given index in a slab.
+    fn object(&self, slab: *mut SlabHeader, index: u16) -> *mut T {
+        ((slab as usize).align_down(PAGE_SIZE) + index as usize * Self::object_size()) as *mut T
+    }
+
+    /// Offset of the header in a slab holding `count` objects.
+    fn header_offset(count: usize) -> usize {
+        (count * Self::object_size()).align_up(mem::align_of::<SlabHeader>())
+    }
+
+    /// Offset of the free list links in a slab holding `count` objects.
+    fn links_offset(count: usize) -> usize {
+        Self::header_offset(count) + mem::size_of::<SlabHeader>()
+    }
+}
+
+impl<T> ObjectCache for SlabCache<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn stats(&self) -> SlabCacheStats {
+        self.stats
+    }
+
+    unsafe fn shrink(&mut self, pfa: &mut PageFrameAllocator) -> usize {
+        SlabCache::shrink(self, pfa)
+    }
+}
+
+/// The free list links of a slab, directly following its header.
+unsafe fn links(slab: *mut SlabHeader) -> *mut u16 {
+    slab.add(1) as *mut u16
+}
+
+unsafe fn push(list: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    (*slab).prev = ptr::null_mut();
+    (*slab).next = *list;
+    if ! list.is_null() {
+        (**list).prev = slab;
+    }
+    *list = slab;
+}
+
+unsafe fn unlink(list: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    if (*slab).prev.is_null() {
+        *list = (*slab).next;
+    } else {
+        (*(*slab).prev).next = (*slab).next;
+    }
+    if ! (*slab).next.is_null() {
+        (*(*slab).next).prev = (*slab).prev;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amd64::PhysAddr;
+    use crate::physical::alloc::BuddyPageFrameAllocator;
+    use crate::physical::mgmt::{PageFrameTable, PageFrameInfo};
+    use super::*;
+
+    const FRAME_COUNT: usize = 16;
+
+    #[repr(C, align(4096))]
+    struct Page([u8; PAGE_SIZE]);
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Object {
+        id: u64,
+        value: u32,
+    }
+
+    fn make_object() -> Object {
+        Object { id: 7, value: 42 }
+    }
+
+    /// Host memory standing in for physical memory, together with an allocator for its frames.
+    fn environment(memory: &mut Vec<Page>, storage: &mut Vec<PageFrameInfo>) -> (&'static DirectMapping, BuddyPageFrameAllocator) {
+        memory.extend((0..FRAME_COUNT).map(|_| Page([0; PAGE_SIZE])));
+        storage.reserve_exact(FRAME_COUNT);
+        let mapping = DirectMapping::new(VirtAddr(memory.as_mut_ptr() as usize), PhysAddr(0), FRAME_COUNT * PAGE_SIZE);
+        let pft = unsafe { PageFrameTable::from_addr(VirtAddr(storage.as_mut_ptr() as usize), FRAME_COUNT) };
+        (Box::leak(Box::new(mapping)), BuddyPageFrameAllocator::new(pft))
+    }
+
+    #[test]
+    fn test_slab_geometry() {
+        assert_eq!(SlabCache::<Object>::object_size(), 16);
+        assert_eq!(SlabCache::<Object>::objects_per_slab(), 226);
+        assert_eq!(SlabCache::<u8>::objects_per_slab(), 1356);
+        assert_eq!(SlabCache::<[u8; 4000]>::objects_per_slab(), 1);
+        assert_eq!(SlabCache::<[u8; 4096]>::objects_per_slab(), 0);
+    }
+
+    #[test]
+    fn test_slab_alloc_free_shrink() {
+        let (mut memory, mut storage) = (Vec::new(), Vec::new());
+        let (mapping, mut pfa) = environment(&mut memory, &mut storage);
+        let mut cache = SlabCache::with_constructor("test", mapping, make_object);
+        let per_slab = SlabCache::<Object>::objects_per_slab();
+
+        unsafe {
+            let objects: Vec<NonNull<Object>> = (0..per_slab + 1).map(|_| cache.alloc(&mut pfa).unwrap()).collect();
+            assert!(objects.iter().all(|o| *o.as_ref() == make_object()));
+            assert_eq!(cache.stats(), SlabCacheStats {
+                slabs: 2, empty_slabs: 0, objects_in_use: per_slab + 1, allocations: per_slab + 1, grows: 2, shrinks: 0,
+            });
+            assert_eq!(pfa.free_count(), FRAME_COUNT - 2);
+
+            // the partially used slab is preferred over the one that became empty
+            let (first, second) = objects.split_at(per_slab);
+            for object in first {
+                cache.free(*object);
+            }
+            assert_eq!(cache.stats().empty_slabs, 1);
+            let third = cache.alloc(&mut pfa).unwrap();
+            assert_eq!(third.as_ptr() as usize & !(PAGE_SIZE - 1), second[0].as_ptr() as usize & !(PAGE_SIZE - 1));
+
+            cache.free(third);
+            cache.free(second[0]);
+            assert_eq!(cache.stats().objects_in_use, 0);
+            assert_eq!(cache.shrink(&mut pfa), 2);
+            assert_eq!(cache.stats().slabs, 0);
+            assert_eq!(cache.stats().shrinks, 2);
+            assert_eq!(pfa.free_count(), FRAME_COUNT);
+        }
+    }
+
+    #[test]
+    fn test_slab_exhaustion() {
+        let (mut memory, mut storage) = (Vec::new(), Vec::new());
+        let (mapping, mut pfa) = environment(&mut memory, &mut storage);
+        let mut cache: SlabCache<[u64; 256]> = SlabCache::new("big", mapping);
+        let per_slab = SlabCache::<[u64; 256]>::objects_per_slab();
+        assert_eq!(per_slab, 1);
+
+        unsafe {
+            let objects: Vec<_> = (0..FRAME_COUNT).map(|_| cache.alloc(&mut pfa).unwrap()).collect();
+            assert!(cache.alloc(&mut pfa).is_none());
+            for object in objects {
+                cache.free(object);
+            }
+            assert_eq!(cache.stats().empty_slabs, FRAME_COUNT);
+            // empty slabs are reused before new frames are allocated
+            cache.alloc(&mut pfa).unwrap();
+            assert_eq!(cache.stats().grows, FRAME_COUNT);
+            assert_eq!(cache.shrink(&mut pfa), FRAME_COUNT - 1);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_slab_double_free() {
+        let (mut memory, mut storage) = (Vec::new(), Vec::new());
+        let (mapping, mut pfa) = environment(&mut memory, &mut storage);
+        let mut cache: SlabCache<Object> = SlabCache::new("test", mapping);
+        unsafe {
+            let a = cache.alloc(&mut pfa).unwrap();
+            let _b = cache.alloc(&mut pfa).unwrap();
+            cache.free(a);
+            cache.free(a);
+        }
+    }
+}
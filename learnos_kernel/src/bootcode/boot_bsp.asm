@@ -68,7 +68,7 @@ kernel_main_trampoline:
     add rsp, kernel_virtual_base
     mov rbp, rsp
     ; write KernelArgs structure
-    sub rsp, 32
+    sub rsp, 48
     mov qword [rsp], kernel_start
     mov qword [rsp+8], kernel_end
     mov qword [rsp+16], rbx
@@ -115,7 +115,7 @@ setup_page_tables:
     mov eax, page_tbl_pdp_direct
     or  eax, 0b11
     mov DWORD [page_tbl_pml4 + 256 * 8], eax
-    ; PML4[510] -> page_tbl_pml4
+    ; PML4[510] -> page_tbl_pml4 (recursive mapping, until the kernel builds the direct mapping)
     mov eax, page_tbl_pml4
     or  eax, 0b11
     mov DWORD [page_tbl_pml4 + 510 * 8], eax
@@ -17,6 +17,14 @@ Multiboot2Header:
    dw 6  ; type
    dw 0  ; flags
    dd 8  ; size
+   ; framebuffer tag, asking for a graphics mode (the kernel falls back to VGA text mode without it)
+   dw 5  ; type
+   dw MB2_TAG_FLAG_OPTIONAL  ; flags
+   dd 20 ; size
+   dd 1024 ; width
+   dd 768  ; height
+   dd 32   ; depth
+   dd 0  ; padding, tags are 8 byte aligned
    ; end of header tag
    dw 0  ; type
    dw 0  ; flags
@@ -29,9 +29,12 @@ impl log::Log for VgaLogger {
 
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
-            let mut vga_out = crate::vga::writer();
             let lvl_char = level_prefix(record.level());
-            writeln!(vga_out, "[{}] {}", lvl_char, record.args()).unwrap_or(());
+            if crate::framebuffer::is_initialized() {
+                writeln!(crate::framebuffer::console(), "[{}] {}", lvl_char, record.args()).unwrap_or(());
+            } else {
+                writeln!(crate::vga::writer(), "[{}] {}", lvl_char, record.args()).unwrap_or(());
+            }
         }
     }
 
@@ -93,6 +96,10 @@ pub fn print_multiboot(mb2: &multiboot2::Multiboot2Info) {
         info!("  Available: {} MiB", total_available / 1024 / 1024);
     }
 
+    for fb in mb2.framebuffer() {
+        info!("  Framebuffer: {}x{}x{} at {:p}", fb.width(), fb.height(), fb.bits_per_pixel(), fb.address());
+    }
+
     info!("  CmdLine: {:?}", mb2.boot_cmd_line());
     info!("  Bootloader: {:?}", mb2.bootloader_name());
 }
@@ -0,0 +1,199 @@
+//! Bitmap fonts for the framebuffer console.
+//!
+//! Glyphs are stored row by row, with the most significant bit of each byte being the leftmost pixel
+//! and every row padded to whole bytes. This is the layout used by PC Screen Fonts (PSF), which can be
+//! loaded with `Font::from_psf`, and by the built-in font.
+
+/// A bitmap font with glyphs for a consecutive range of character codes.
+#[derive(Debug, Clone, Copy)]
+pub struct Font {
+    width: usize,
+    height: usize,
+    /// The character code of the first glyph.
+    first_char: usize,
+    glyph_count: usize,
+    /// Number of bytes of a single glyph, which may include padding after the last row.
+    glyph_size: usize,
+    data: &'static [u8],
+}
+
+/// Magic bytes at the start of a PSF1 font.
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+
+/// PSF1 mode flag signalling a font with 512 instead of 256 glyphs.
+const PSF1_MODE_512: u8 = 0x01;
+
+/// Magic bytes at the start of a PSF2 font.
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+
+impl Font {
+    /// A font with 8x8 glyphs for the printable ASCII characters.
+    pub const BUILTIN: Font = Font {
+        width: 8,
+        height: 8,
+        first_char: 0x20,
+        glyph_count: 95,
+        glyph_size: 8,
+        data: &BUILTIN_GLYPHS,
+    };
+
+    /// Load a font in the PSF1 or PSF2 format. The Unicode table of a font, if any, is ignored,
+    /// so character codes are used as glyph indices directly.
+    /// Returns `None` if the data is not a valid PSF font.
+    pub fn from_psf(data: &'static [u8]) -> Option<Font> {
+        let (width, height, glyph_count, glyph_size, header_size) = if data.starts_with(&PSF1_MAGIC) {
+            let mode = *data.get(2)?;
+            let height = *data.get(3)? as usize;
+            let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+            (8, height, glyph_count, height, 4)
+        } else if data.starts_with(&PSF2_MAGIC) {
+            let field = |index: usize| -> Option<usize> {
+                let bytes = data.get(4 * index .. 4 * index + 4)?;
+                Some(bytes.iter().rev().fold(0, |value, &byte| (value << 8) | byte as usize))
+            };
+            (field(7)?, field(6)?, field(4)?, field(5)?, field(2)?)
+        } else {
+            return None;
+        };
+
+        let row_size = (width + 7) / 8;
+        if width == 0 || height == 0 || glyph_size < row_size * height {
+            return None;
+        }
+        let end = header_size.checked_add(glyph_count.checked_mul(glyph_size)?)?;
+        Some(Font {
+            width: width,
+            height: height,
+            first_char: 0,
+            glyph_count: glyph_count,
+            glyph_size: glyph_size,
+            data: data.get(header_size..end)?,
+        })
+    }
+
+    /// Width of a glyph in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height of a glyph in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The bitmap of the glyph for a character, if the font has one.
+    pub fn glyph(&self, ch: u8) -> Option<&'static [u8]> {
+        let index = (ch as usize).checked_sub(self.first_char)?;
+        if index < self.glyph_count {
+            Some(&self.data[index * self.glyph_size .. (index + 1) * self.glyph_size])
+        } else {
+            None
+        }
+    }
+
+    /// Whether the pixel at the given position of a glyph returned by `glyph` is set.
+    pub fn is_set(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        let row_size = (self.width + 7) / 8;
+        glyph[y * row_size + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+/// The glyphs of `Font::BUILTIN`, taken from the public domain font8x8 by Daniel Hepper,
+/// which is based on the IBM PC BIOS font.
+const BUILTIN_GLYPHS: [u8; 95 * 8] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // U+0020 (space)
+    0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00,  // U+0021 !
+    0x6C, 0x6C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // U+0022 "
+    0x6C, 0x6C, 0xFE, 0x6C, 0xFE, 0x6C, 0x6C, 0x00,  // U+0023 #
+    0x30, 0x7C, 0xC0, 0x78, 0x0C, 0xF8, 0x30, 0x00,  // U+0024 $
+    0x00, 0xC6, 0xCC, 0x18, 0x30, 0x66, 0xC6, 0x00,  // U+0025 %
+    0x38, 0x6C, 0x38, 0x76, 0xDC, 0xCC, 0x76, 0x00,  // U+0026 &
+    0x60, 0x60, 0xC0, 0x00, 0x00, 0x00, 0x00, 0x00,  // U+0027 '
+    0x18, 0x30, 0x60, 0x60, 0x60, 0x30, 0x18, 0x00,  // U+0028 (
+    0x60, 0x30, 0x18, 0x18, 0x18, 0x30, 0x60, 0x00,  // U+0029 )
+    0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00,  // U+002A *
+    0x00, 0x30, 0x30, 0xFC, 0x30, 0x30, 0x00, 0x00,  // U+002B +
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x30, 0x60,  // U+002C ,
+    0x00, 0x00, 0x00, 0xFC, 0x00, 0x00, 0x00, 0x00,  // U+002D -
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x30, 0x30, 0x00,  // U+002E .
+    0x06, 0x0C, 0x18, 0x30, 0x60, 0xC0, 0x80, 0x00,  // U+002F /
+    0x7C, 0xC6, 0xCE, 0xDE, 0xF6, 0xE6, 0x7C, 0x00,  // U+0030 0
+    0x30, 0x70, 0x30, 0x30, 0x30, 0x30, 0xFC, 0x00,  // U+0031 1
+    0x78, 0xCC, 0x0C, 0x38, 0x60, 0xCC, 0xFC, 0x00,  // U+0032 2
+    0x78, 0xCC, 0x0C, 0x38, 0x0C, 0xCC, 0x78, 0x00,  // U+0033 3
+    0x1C, 0x3C, 0x6C, 0xCC, 0xFE, 0x0C, 0x1E, 0x00,  // U+0034 4
+    0xFC, 0xC0, 0xF8, 0x0C, 0x0C, 0xCC, 0x78, 0x00,  // U+0035 5
+    0x38, 0x60, 0xC0, 0xF8, 0xCC, 0xCC, 0x78, 0x00,  // U+0036 6
+    0xFC, 0xCC, 0x0C, 0x18, 0x30, 0x30, 0x30, 0x00,  // U+0037 7
+    0x78, 0xCC, 0xCC, 0x78, 0xCC, 0xCC, 0x78, 0x00,  // U+0038 8
+    0x78, 0xCC, 0xCC, 0x7C, 0x0C, 0x18, 0x70, 0x00,  // U+0039 9
+    0x00, 0x30, 0x30, 0x00, 0x00, 0x30, 0x30, 0x00,  // U+003A :
+    0x00, 0x30, 0x30, 0x00, 0x00, 0x30, 0x30, 0x60,  // U+003B ;
+    0x18, 0x30, 0x60, 0xC0, 0x60, 0x30, 0x18, 0x00,  // U+003C <
+    0x00, 0x00, 0xFC, 0x00, 0x00, 0xFC, 0x00, 0x00,  // U+003D =
+    0x60, 0x30, 0x18, 0x0C, 0x18, 0x30, 0x60, 0x00,  // U+003E >
+    0x78, 0xCC, 0x0C, 0x18, 0x30, 0x00, 0x30, 0x00,  // U+003F ?
+    0x7C, 0xC6, 0xDE, 0xDE, 0xDE, 0xC0, 0x78, 0x00,  // U+0040 @
+    0x30, 0x78, 0xCC, 0xCC, 0xFC, 0xCC, 0xCC, 0x00,  // U+0041 A
+    0xFC, 0x66, 0x66, 0x7C, 0x66, 0x66, 0xFC, 0x00,  // U+0042 B
+    0x3C, 0x66, 0xC0, 0xC0, 0xC0, 0x66, 0x3C, 0x00,  // U+0043 C
+    0xF8, 0x6C, 0x66, 0x66, 0x66, 0x6C, 0xF8, 0x00,  // U+0044 D
+    0xFE, 0x62, 0x68, 0x78, 0x68, 0x62, 0xFE, 0x00,  // U+0045 E
+    0xFE, 0x62, 0x68, 0x78, 0x68, 0x60, 0xF0, 0x00,  // U+0046 F
+    0x3C, 0x66, 0xC0, 0xC0, 0xCE, 0x66, 0x3E, 0x00,  // U+0047 G
+    0xCC, 0xCC, 0xCC, 0xFC, 0xCC, 0xCC, 0xCC, 0x00,  // U+0048 H
+    0x78, 0x30, 0x30, 0x30, 0x30, 0x30, 0x78, 0x00,  // U+0049 I
+    0x1E, 0x0C, 0x0C, 0x0C, 0xCC, 0xCC, 0x78, 0x00,  // U+004A J
+    0xE6, 0x66, 0x6C, 0x78, 0x6C, 0x66, 0xE6, 0x00,  // U+004B K
+    0xF0, 0x60, 0x60, 0x60, 0x62, 0x66, 0xFE, 0x00,  // U+004C L
+    0xC6, 0xEE, 0xFE, 0xFE, 0xD6, 0xC6, 0xC6, 0x00,  // U+004D M
+    0xC6, 0xE6, 0xF6, 0xDE, 0xCE, 0xC6, 0xC6, 0x00,  // U+004E N
+    0x38, 0x6C, 0xC6, 0xC6, 0xC6, 0x6C, 0x38, 0x00,  // U+004F O
+    0xFC, 0x66, 0x66, 0x7C, 0x60, 0x60, 0xF0, 0x00,  // U+0050 P
+    0x78, 0xCC, 0xCC, 0xCC, 0xDC, 0x78, 0x1C, 0x00,  // U+0051 Q
+    0xFC, 0x66, 0x66, 0x7C, 0x6C, 0x66, 0xE6, 0x00,  // U+0052 R
+    0x78, 0xCC, 0xE0, 0x70, 0x1C, 0xCC, 0x78, 0x00,  // U+0053 S
+    0xFC, 0xB4, 0x30, 0x30, 0x30, 0x30, 0x78, 0x00,  // U+0054 T
+    0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xFC, 0x00,  // U+0055 U
+    0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0x78, 0x30, 0x00,  // U+0056 V
+    0xC6, 0xC6, 0xC6, 0xD6, 0xFE, 0xEE, 0xC6, 0x00,  // U+0057 W
+    0xC6, 0xC6, 0x6C, 0x38, 0x38, 0x6C, 0xC6, 0x00,  // U+0058 X
+    0xCC, 0xCC, 0xCC, 0x78, 0x30, 0x30, 0x78, 0x00,  // U+0059 Y
+    0xFE, 0xC6, 0x8C, 0x18, 0x32, 0x66, 0xFE, 0x00,  // U+005A Z
+    0x78, 0x60, 0x60, 0x60, 0x60, 0x60, 0x78, 0x00,  // U+005B [
+    0xC0, 0x60, 0x30, 0x18, 0x0C, 0x06, 0x02, 0x00,  // U+005C \
+    0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0x78, 0x00,  // U+005D ]
+    0x10, 0x38, 0x6C, 0xC6, 0x00, 0x00, 0x00, 0x00,  // U+005E ^
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF,  // U+005F _
+    0x30, 0x30, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00,  // U+0060 `
+    0x00, 0x00, 0x78, 0x0C, 0x7C, 0xCC, 0x76, 0x00,  // U+0061 a
+    0xE0, 0x60, 0x60, 0x7C, 0x66, 0x66, 0xDC, 0x00,  // U+0062 b
+    0x00, 0x00, 0x78, 0xCC, 0xC0, 0xCC, 0x78, 0x00,  // U+0063 c
+    0x1C, 0x0C, 0x0C, 0x7C, 0xCC, 0xCC, 0x76, 0x00,  // U+0064 d
+    0x00, 0x00, 0x78, 0xCC, 0xFC, 0xC0, 0x78, 0x00,  // U+0065 e
+    0x38, 0x6C, 0x60, 0xF0, 0x60, 0x60, 0xF0, 0x00,  // U+0066 f
+    0x00, 0x00, 0x76, 0xCC, 0xCC, 0x7C, 0x0C, 0xF8,  // U+0067 g
+    0xE0, 0x60, 0x6C, 0x76, 0x66, 0x66, 0xE6, 0x00,  // U+0068 h
+    0x30, 0x00, 0x70, 0x30, 0x30, 0x30, 0x78, 0x00,  // U+0069 i
+    0x0C, 0x00, 0x0C, 0x0C, 0x0C, 0xCC, 0xCC, 0x78,  // U+006A j
+    0xE0, 0x60, 0x66, 0x6C, 0x78, 0x6C, 0xE6, 0x00,  // U+006B k
+    0x70, 0x30, 0x30, 0x30, 0x30, 0x30, 0x78, 0x00,  // U+006C l
+    0x00, 0x00, 0xCC, 0xFE, 0xFE, 0xD6, 0xC6, 0x00,  // U+006D m
+    0x00, 0x00, 0xF8, 0xCC, 0xCC, 0xCC, 0xCC, 0x00,  // U+006E n
+    0x00, 0x00, 0x78, 0xCC, 0xCC, 0xCC, 0x78, 0x00,  // U+006F o
+    0x00, 0x00, 0xDC, 0x66, 0x66, 0x7C, 0x60, 0xF0,  // U+0070 p
+    0x00, 0x00, 0x76, 0xCC, 0xCC, 0x7C, 0x0C, 0x1E,  // U+0071 q
+    0x00, 0x00, 0xDC, 0x76, 0x66, 0x60, 0xF0, 0x00,  // U+0072 r
+    0x00, 0x00, 0x7C, 0xC0, 0x78, 0x0C, 0xF8, 0x00,  // U+0073 s
+    0x10, 0x30, 0x7C, 0x30, 0x30, 0x34, 0x18, 0x00,  // U+0074 t
+    0x00, 0x00, 0xCC, 0xCC, 0xCC, 0xCC, 0x76, 0x00,  // U+0075 u
+    0x00, 0x00, 0xCC, 0xCC, 0xCC, 0x78, 0x30, 0x00,  // U+0076 v
+    0x00, 0x00, 0xC6, 0xD6, 0xFE, 0xFE, 0x6C, 0x00,  // U+0077 w
+    0x00, 0x00, 0xC6, 0x6C, 0x38, 0x6C, 0xC6, 0x00,  // U+0078 x
+    0x00, 0x00, 0xCC, 0xCC, 0xCC, 0x7C, 0x0C, 0xF8,  // U+0079 y
+    0x00, 0x00, 0xFC, 0x98, 0x30, 0x64, 0xFC, 0x00,  // U+007A z
+    0x1C, 0x30, 0x30, 0xE0, 0x30, 0x30, 0x1C, 0x00,  // U+007B {
+    0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00,  // U+007C |
+    0xE0, 0x30, 0x30, 0x1C, 0x30, 0x30, 0xE0, 0x00,  // U+007D }
+    0x76, 0xDC, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,  // U+007E ~
+];
@@ -0,0 +1,365 @@
+//! A text console on the linear framebuffer set up by the bootloader.
+//!
+//! When the bootloader honors the request for a graphics mode in the multiboot header, the VGA
+//! text buffer is no longer displayed. Text is then rendered into the framebuffer using a bitmap
+//! font instead. The console behaves like the VGA one, scrolling up when reaching the bottom.
+//!
+//! The framebuffer is mapped uncached, which makes reading it back very slow. The console therefore
+//! remembers the characters on the screen, and scrolls by redrawing those that change.
+
+use amd64::VirtAddr;
+use core::fmt;
+use multiboot2::framebuffer::{ColorField, FramebufferFormat, FramebufferTag, PaletteEntry};
+
+use crate::vga::Color;
+
+mod font;
+pub use self::font::Font;
+
+/// Provides a single synchronized access to the framebuffer console.
+pub static GLOBAL_CONSOLE: spin::Mutex<Option<Console>> = spin::Mutex::new(None);
+
+/// The framebuffer used by the global console, for creating an emergency console when it is locked.
+static FRAMEBUFFER: spin::Once<Framebuffer> = spin::Once::new();
+
+/// Maximum number of characters per line of a console.
+const MAX_COLUMNS: usize = 256;
+
+/// Maximum number of lines of a console.
+const MAX_ROWS: usize = 160;
+
+/// The characters on the screen, row by row. All consoles share them, just like the framebuffer.
+static mut CELLS: [Cell; MAX_COLUMNS * MAX_ROWS] = [Cell { ch: b' ', fg: Color::White, bg: Color::Black }; MAX_COLUMNS * MAX_ROWS];
+
+/// Initialize the global console on the given framebuffer.
+pub fn init(framebuffer: Framebuffer) {
+    FRAMEBUFFER.call_once(|| framebuffer.clone());
+    *GLOBAL_CONSOLE.lock() = Some(Console::new(framebuffer, Font::BUILTIN));
+}
+
+/// Whether the global console has been initialized, i.e. whether the screen is in a graphics mode.
+pub fn is_initialized() -> bool {
+    FRAMEBUFFER.r#try().is_some()
+}
+
+pub const fn console() -> ConsoleHandle {
+    ConsoleHandle
+}
+
+/// Create a second console on the framebuffer of the global one, for when all else has failed.
+/// This is unsafe, because both consoles write to the same memory.
+pub unsafe fn emergency_console(fg: Color, bg: Color) -> Option<Console> {
+    FRAMEBUFFER.r#try().map(|framebuffer| Console::with_colors(framebuffer.clone(), Font::BUILTIN, fg, bg))
+}
+
+/// Handle to the globally synchronized framebuffer console.
+#[derive(Debug)]
+pub struct ConsoleHandle;
+
+impl fmt::Write for ConsoleHandle {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut console_guard = GLOBAL_CONSOLE.lock();
+        let console = (*console_guard).as_mut().ok_or(fmt::Error)?;
+        console.write_str(s)
+    }
+}
+
+/// How pixel values are derived from colors.
+#[derive(Clone, Copy)]
+enum PixelFormat {
+    Rgb { red: ColorField, green: ColorField, blue: ColorField },
+    /// The palette is copied, because the multiboot information it is part of is freed eventually.
+    Indexed { palette: [PaletteEntry; 256], count: usize },
+}
+
+/// A linear framebuffer in a graphics mode.
+#[derive(Clone)]
+pub struct Framebuffer {
+    base: *mut u8,
+    /// Number of bytes per line.
+    pitch: usize,
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    format: PixelFormat,
+}
+
+/// One thread at a time may access the framebuffer.
+unsafe impl Send for Framebuffer {}
+unsafe impl Sync for Framebuffer {}
+
+impl Framebuffer {
+    /// Create a wrapper for the framebuffer described by the multiboot tag, which is mapped at `base`.
+    /// Returns `None` for text modes and unsupported pixel formats.
+    pub unsafe fn from_tag(tag: &FramebufferTag, base: VirtAddr) -> Option<Framebuffer> {
+        let bytes_per_pixel = match tag.bits_per_pixel() {
+            8 => 1,
+            15 | 16 => 2,
+            24 => 3,
+            32 => 4,
+            _ => return None,
+        };
+        let format = match tag.format() {
+            FramebufferFormat::Rgb { red, green, blue } => PixelFormat::Rgb { red: red, green: green, blue: blue },
+            FramebufferFormat::Indexed(entries) => {
+                let mut palette = [PaletteEntry { red: 0, green: 0, blue: 0 }; 256];
+                let count = entries.len().min(palette.len());
+                palette[0..count].copy_from_slice(&entries[0..count]);
+                PixelFormat::Indexed { palette: palette, count: count }
+            },
+            _ => return None,
+        };
+        Some(Framebuffer {
+            base: base.as_mut_ptr(),
+            pitch: tag.pitch(),
+            width: tag.width(),
+            height: tag.height(),
+            bytes_per_pixel: bytes_per_pixel,
+            format: format,
+        })
+    }
+
+    /// Width in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// The pixel value closest to the given color.
+    pub fn pixel(&self, red: u8, green: u8, blue: u8) -> u32 {
+        match self.format {
+            PixelFormat::Rgb { red: red_field, green: green_field, blue: blue_field } => {
+                let component = |value: u8, field: ColorField| {
+                    ((value as u32) >> (8 - field.size.min(8))) << field.position
+                };
+                component(red, red_field) | component(green, green_field) | component(blue, blue_field)
+            },
+            PixelFormat::Indexed { ref palette, count } => {
+                let distance = |entry: &PaletteEntry| {
+                    let delta = |a: u8, b: u8| (a as i32 - b as i32) * (a as i32 - b as i32);
+                    delta(entry.red, red) + delta(entry.green, green) + delta(entry.blue, blue)
+                };
+                palette[0..count].iter().enumerate()
+                    .min_by_key(|(_, entry)| distance(entry))
+                    .map_or(0, |(index, _)| index as u32)
+            },
+        }
+    }
+
+    /// The pixel value closest to one of the VGA colors.
+    pub fn vga_pixel(&self, color: Color) -> u32 {
+        let (red, green, blue) = vga_rgb(color);
+        self.pixel(red, green, blue)
+    }
+
+    /// Set the pixel at the given position.
+    #[inline]
+    pub fn put_pixel(&mut self, x: usize, y: usize, value: u32) {
+        assert!(x < self.width && y < self.height);
+        let offset = y * self.pitch + x * self.bytes_per_pixel;
+        for i in 0..self.bytes_per_pixel {
+            unsafe { self.base.add(offset + i).write_volatile((value >> (8 * i)) as u8) }
+        }
+    }
+
+    /// Set all pixels of a rectangle, clipped to the framebuffer.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, value: u32) {
+        for row in y..(y + height).min(self.height) {
+            for column in x..(x + width).min(self.width) {
+                self.put_pixel(column, row, value);
+            }
+        }
+    }
+}
+
+/// The RGB values of the VGA colors.
+fn vga_rgb(color: Color) -> (u8, u8, u8) {
+    match color {
+        Color::Black => (0x00, 0x00, 0x00),
+        Color::Blue => (0x00, 0x00, 0xAA),
+        Color::Green => (0x00, 0xAA, 0x00),
+        Color::Cyan => (0x00, 0xAA, 0xAA),
+        Color::Red => (0xAA, 0x00, 0x00),
+        Color::Magenta => (0xAA, 0x00, 0xAA),
+        Color::Brown => (0xAA, 0x55, 0x00),
+        Color::LightGray => (0xAA, 0xAA, 0xAA),
+        Color::DarkGray => (0x55, 0x55, 0x55),
+        Color::LightBlue => (0x55, 0x55, 0xFF),
+        Color::LightGreen => (0x55, 0xFF, 0x55),
+        Color::LightCyan => (0x55, 0xFF, 0xFF),
+        Color::LightRed => (0xFF, 0x55, 0x55),
+        Color::LightMagenta => (0xFF, 0x55, 0xFF),
+        Color::Yellow => (0xFF, 0xFF, 0x55),
+        Color::White => (0xFF, 0xFF, 0xFF),
+    }
+}
+
+/// A character on the screen along with its colors.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: u8,
+    fg: Color,
+    bg: Color,
+}
+
+/// A write-only text console rendering characters into a framebuffer.
+pub struct Console {
+    framebuffer: Framebuffer,
+    font: Font,
+    /// number of characters per line
+    columns: usize,
+    /// number of lines
+    rows: usize,
+    // current output column
+    x: usize,
+    // current output line
+    y: usize,
+    /// current foreground color
+    fg: Color,
+    /// current background color
+    bg: Color,
+    /// pixel values of the VGA colors, indexed by their code
+    pixels: [u32; 16],
+    /// the characters on the screen, see `CELLS`
+    cells: *mut Cell,
+}
+
+/// Consoles are only used behind a lock, or by a panicking thread when everything else has failed.
+unsafe impl Send for Console {}
+
+impl Console {
+    /// Build a new console on top of the framebuffer.
+    pub fn new(framebuffer: Framebuffer, font: Font) -> Console {
+        Self::with_colors(framebuffer, font, Color::White, Color::Black)
+    }
+
+    /// Build a new console with the given initial colors.
+    pub fn with_colors(framebuffer: Framebuffer, font: Font, fg: Color, bg: Color) -> Console {
+        let mut pixels = [0; 16];
+        for (code, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = Color::from_vga(code as u8).map_or(0, |color| framebuffer.vga_pixel(color));
+        }
+        let mut con = Console {
+            columns: (framebuffer.width() / font.width()).min(MAX_COLUMNS),
+            rows: (framebuffer.height() / font.height()).min(MAX_ROWS),
+            fg: fg,
+            bg: bg,
+            pixels: pixels,
+            cells: unsafe { CELLS.as_mut_ptr() },
+            framebuffer: framebuffer,
+            font: font,
+            x: 0,
+            y: 0,
+        };
+        con.clear();
+        con
+    }
+
+    /// Set the colors that are used for subsequent writes.
+    pub fn set_colors(&mut self, fg: Color, bg: Color) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    /// Switch to a different font, which clears the console.
+    pub fn set_font(&mut self, font: Font) {
+        self.font = font;
+        self.columns = (self.framebuffer.width() / font.width()).min(MAX_COLUMNS);
+        self.rows = (self.framebuffer.height() / font.height()).min(MAX_ROWS);
+        self.clear();
+    }
+
+    /// Clear the framebuffer and reset the cursor to the top left.
+    pub fn clear(&mut self) {
+        let (width, height, bg) = (self.framebuffer.width(), self.framebuffer.height(), self.pixels[self.bg as usize]);
+        self.framebuffer.fill_rect(0, 0, width, height, bg);
+        let blank = self.blank();
+        for i in 0..self.rows * self.columns {
+            unsafe { *self.cells.add(i) = blank };
+        }
+        self.x = 0;
+        self.y = 0;
+    }
+
+    /// Write a single character.
+    /// This advances the cursor one step to the right.
+    /// A newline character causes the cursor to be set at the start of the next line.
+    pub fn write_char(&mut self, ch: u8) {
+        if ch == b'\n' {
+            self.next_line();
+        } else {
+            let (x, y, cell) = (self.x, self.y, Cell { ch: ch, fg: self.fg, bg: self.bg });
+            self.set_cell(x, y, cell);
+            self.x += 1;
+            if self.x == self.columns {
+                self.next_line();
+            }
+        }
+    }
+
+    /// Advance the cursor to the next line.
+    pub fn next_line(&mut self) {
+        if self.y + 1 >= self.rows {
+            self.scroll_up();
+        } else {
+            self.y += 1;
+        }
+        self.x = 0;
+    }
+
+    /// Move all lines up by one, leaving an empty line at the bottom.
+    /// Only the characters that actually change are drawn again.
+    fn scroll_up(&mut self) {
+        for y in 0..self.rows {
+            for x in 0..self.columns {
+                let cell = if y + 1 < self.rows { self.cell(x, y + 1) } else { self.blank() };
+                if cell != self.cell(x, y) {
+                    self.set_cell(x, y, cell);
+                }
+            }
+        }
+    }
+
+    /// An empty cell in the current colors.
+    fn blank(&self) -> Cell {
+        Cell { ch: b' ', fg: self.fg, bg: self.bg }
+    }
+
+    fn cell(&self, x: usize, y: usize) -> Cell {
+        unsafe { *self.cells.add(y * self.columns + x) }
+    }
+
+    /// Put a character on the screen at the given position.
+    fn set_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        unsafe { *self.cells.add(y * self.columns + x) = cell };
+        self.draw_cell(x, y, cell);
+    }
+
+    /// Render a character at the given position. Characters without a glyph are drawn as blanks.
+    fn draw_cell(&mut self, x: usize, y: usize, cell: Cell) {
+        let (left, top) = (x * self.font.width(), y * self.font.height());
+        let (fg, bg) = (self.pixels[cell.fg as usize], self.pixels[cell.bg as usize]);
+        let glyph = self.font.glyph(cell.ch);
+        for y in 0..self.font.height() {
+            for x in 0..self.font.width() {
+                let set = glyph.map_or(false, |glyph| self.font.is_set(glyph, x, y));
+                self.framebuffer.put_pixel(left + x, top + y, if set { fg } else { bg });
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.bytes() {
+            if ch <= 0x7F {
+                self.write_char(ch);
+            }
+        }
+        Ok(())
+    }
+}
@@ -5,7 +5,7 @@
 #![feature(asm)]
 #![feature(get_type_id)]
 #![feature(const_fn)]
-//#![feature(alloc)]
+#![feature(alloc)]
 #![feature(format_args_nl)] // needed for debug! macro
 #![feature(extern_crate_item_prelude)]
 #![feature(alloc_error_handler)]
@@ -20,7 +20,7 @@ extern crate log;
 extern crate spin;
 #[macro_use]
 extern crate lazy_static;
-//extern crate alloc;
+extern crate alloc;
 
 // crates from crates.io
 #[macro_use]
@@ -34,26 +34,30 @@ extern crate amd64;
 extern crate kmem;
 extern crate multiboot2;
 
-use core::cmp;
-use core::iter;
 
 use acpi::AcpiTable;
 use amd64::*;
 use amd64::segments::Ring;
 use amd64::idt::{IdtEntry, Idt};
+use amd64::gdt::{GdtEntry, Gdt};
 use amd64::apic::{ApicRegisters, TriggerMode, Polarity, LvtTimerEntry, TimerDivisor};
 use amd64::ioapic::{IoApicRegisters};
 use kmem::physical::{PageFrameRegion, PageFrame};
 use kmem::physical::alloc::PageFrameAllocator;
-use kmem::physical::mgmt::{PageFrameTable};
+use kmem::physical::bootmem::BootMemAllocator;
+use kmem::physical::mgmt::{PageFrameTable, PageFrameOwner};
+use kmem::physical::regions::RegionSet;
+use kmem::physical::zone::Zone;
 
 #[macro_use]
 pub mod diagnostics;
 pub mod globals;
 pub mod vga;
+pub mod framebuffer;
 pub mod panic;
 pub mod mem;
 pub mod smp;
+pub mod symbols;
 
 use self::mem::layout::DIRECT_MAPPING;
 
@@ -81,11 +85,25 @@ assert_eq_size!(ptr_size; usize, u64);
 /// The IDT that is used by the kernel on all cores.
 static IDT: spin::Mutex<Idt> = spin::Mutex::new(Idt::new());
 
+/// The GDT that is used by the kernel on all cores, replacing the one of the boot code.
+static GDT: spin::Mutex<Gdt> = spin::Mutex::new(Gdt::new());
+
+/// The arguments passed by the loader, kept around after leaving the boot stack.
+static KERNEL_ARGS: spin::Once<KernelArgs> = spin::Once::new();
+
+/// The kernel heap, backing `Box`, `Vec` and friends.
+#[global_allocator]
+static ALLOCATOR: mem::heap::KernelAllocator = mem::heap::KernelAllocator::new();
+
 static LOGGER: &'static log::Log = &diagnostics::FanOutLogger
     (diagnostics::SerialLogger, diagnostics::VgaLogger);
 
 static APIC: ApicRegisters = ApicRegisters::new(core::ptr::null_mut());
 
+/// Whether page faults log all mappings of the active address space, enabled by the `pfdump`
+/// command line option. The dump is long and walks page tables that might be what is broken.
+static DUMP_ON_PAGE_FAULT: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
 lazy_static! {
     static ref CPUS: spin::RwLock<smp::CpuTable> = spin::RwLock::new(smp::CpuTable::new());
     static ref IOAPICS: spin::RwLock<smp::IoApicTable> = spin::RwLock::new(smp::IoApicTable::new());
@@ -97,13 +115,13 @@ mod selectors {
     use amd64::segments::Selector;
 
     pub const KERNEL_CODE: Selector = Selector(8);
-    #[allow(dead_code)]
     pub const KERNEL_DATA: Selector = Selector(16);
 }
 
 /// This is the Rust entry point that is called by the assembly boot code after switching to long mode.
 #[no_mangle]
 pub extern "C" fn kernel_main(args: &KernelArgs) -> ! {
+    let args = KERNEL_ARGS.call_once(|| *args);
     vga::init(DIRECT_MAPPING.phys_to_virt(vga::VGA_PHYS_ADDR));
     log::set_logger(LOGGER)
         .map(|()| log::set_max_level(log::LevelFilter::Trace))
@@ -111,24 +129,137 @@ pub extern "C" fn kernel_main(args: &KernelArgs) -> ! {
 
     debug!("VGA initialized");
 
+    // all page tables built by the kernel rely on the no-execute bit
+    unsafe {
+        if ! amd64::msr::enable_no_execute() {
+            panic!("no-execute bit not supported")
+        }
+    }
+
     // parse multiboot info
     let mb2: &multiboot2::Multiboot2Info = unsafe { &*DIRECT_MAPPING.phys_to_virt(args.multiboot_start).as_ptr() };
+
+    // switch to the framebuffer console right away if the boot mapping reaches the framebuffer,
+    // otherwise once the direct mapping has been built
+    let early_framebuffer = mb2.framebuffer().map_or(false, |tag|
+        tag.address() + tag.size_in_bytes() <= PhysAddr(mem::layout::BOOT_DIRECT_MAPPING_SIZE));
+    if early_framebuffer {
+        unsafe { init_framebuffer_console(mb2.framebuffer().unwrap()) };
+    }
     diagnostics::print_multiboot(&mb2);
+    DUMP_ON_PAGE_FAULT.store(has_boot_option(mb2, "pfdump"), core::sync::atomic::Ordering::Relaxed);
 
     let page_frame_table = unsafe { initialize_page_frame_table(args, mb2) };
+    // the slow allocator works on the table alone, so frames can still be marked defective afterwards
     let mut pfa = kmem::physical::alloc::SlowPageFrameAllocator::new(page_frame_table);
 
+    // make all physical memory reachable before the allocator hands out frames above the boot mapping
+    unsafe {
+        let memory_map = mb2.memory_map().expect("Bootloader did not provide memory map.").sanitized();
+        let mmio = [
+            (vga::VGA_PHYS_ADDR, vga::VgaMem::SIZE * 2),
+            (amd64::apic::base_address(), kmem::PAGE_SIZE),
+            mb2.framebuffer().map_or((PhysAddr(0), 0), |tag| (tag.address(), tag.size_in_bytes())),
+        ];
+        mem::direct::init(&memory_map, &mmio, &mut pfa);
+    }
+    let mut page_frame_table = pfa.into_page_frame_table();
+    unsafe { run_memtest(mb2, &mut page_frame_table) };
+    mem::frames::init(kmem::physical::alloc::BuddyPageFrameAllocator::new(page_frame_table));
+
+    match mb2.framebuffer() {
+        Some(tag) if ! early_framebuffer => unsafe { init_framebuffer_console(tag) },
+        Some(_) => {},
+        None => info!("No framebuffer, keeping VGA text mode"),
+    }
+
+    {
+        let pfa = mem::frames::global().lock();
+        let stats = pfa.page_frame_table().stats();
+        debug!("[kmem] {} frames, {} reserved, {} defective, {} allocated",
+            stats.total_count, stats.reserved_count, stats.defective_count, stats.allocated_count);
+        for owner in PageFrameOwner::ALL.iter().cloned() {
+            debug!("[kmem]   {:?}: {} frames", owner, stats.owner_count(owner));
+        }
+        for zone in Zone::ALL.iter().cloned() {
+            let region = pfa.page_frame_table().zone_region(zone);
+            if ! region.is_empty() {
+                debug!("[kmem] zone {:?}: {:p} - {:p}, {} of {} frames free", zone,
+                    region.start.start_address(), region.end.start_address(),
+                    pfa.zone_free_count(zone), region.length());
+            }
+        }
+    }
+
     unsafe {
+        let mut pfa = mem::frames::global().lock();
         let p = pfa.alloc_region(32).unwrap();
         debug!("test {:?}", p);
         pfa.free_region(p);
+        let l = pfa.alloc_large_page().unwrap();
+        debug!("test {:?}", l);
+        pfa.free_region(l);
     }
 
-    // TODO: setup allocator
+    unsafe {
+        let f = mem::frames::alloc().unwrap();
+        debug!("test {:?}", f);
+        mem::frames::free(f);
+    }
 
-    // TODO: setup proper address space
+    // the GDT of the boot code is about to be freed
+    unsafe {
+        let mut gdt = GDT.lock();
+        gdt[1] = GdtEntry::KERNEL_CODE;
+        gdt[2] = GdtEntry::KERNEL_DATA;
+        amd64::gdt::load_gdt(&*gdt, selectors::KERNEL_CODE, selectors::KERNEL_DATA);
+        debug!("GDT loaded");
+    }
+
+    unsafe { mem::space::enter(args.kernel_start, args.kernel_end, kernel_main_continued) }
+}
 
-    // TODO: setup proper GDT
+/// Continuation of `kernel_main` on the kernel stack, once the page tables of the boot code are gone.
+extern "C" fn kernel_main_continued() -> ! {
+    let args = KERNEL_ARGS.wait().expect("kernel arguments missing");
+    unsafe {
+        let mb2: &multiboot2::Multiboot2Info = &*DIRECT_MAPPING.phys_to_virt(args.multiboot_start).as_ptr();
+        symbols::init(mb2);
+        reclaim_boot_memory(args);
+    }
+
+    unsafe {
+        let buffer = mem::vmalloc::vmalloc(16, kmem::paging::MapFlags::KERNEL_DATA).unwrap();
+        debug!("test {:?}", buffer);
+        mem::vmalloc::vfree(buffer);
+    }
+
+    {
+        let boxed = alloc::boxed::Box::new(42u64);
+        let squares: alloc::vec::Vec<u64> = (0..1000).map(|x| x * x).collect();
+        let mut names = alloc::collections::BTreeMap::new();
+        names.insert(*boxed, "answer");
+        debug!("test {:?} {} {:?}", boxed, squares.iter().sum::<u64>(), names);
+        let (size, free) = ALLOCATOR.stats();
+        debug!("[kmem] heap: {} of {} bytes free", free, size);
+        #[cfg(feature = "heap-debug")]
+        ALLOCATOR.dump_live_allocations();
+    }
+
+    unsafe {
+        // not registered, the cache is gone once its slabs have been given back
+        let test_cache: mem::slab::KernelCache<[u64; 8]> =
+            spin::Mutex::new(kmem::slab::SlabCache::new("test", &DIRECT_MAPPING));
+        let objects: alloc::vec::Vec<_> = (0..100).map(|_| mem::slab::alloc(&test_cache).unwrap()).collect();
+        debug!("test {:?}", objects[0]);
+        for object in objects {
+            mem::slab::free(&test_cache, object);
+        }
+        let stats = test_cache.lock().stats();
+        debug!("[kmem] slab test: {} slabs, {} allocations", stats.slabs, stats.allocations);
+        debug!("[kmem] reclaimed {} slab frames", mem::frames::with_local(|pfa| test_cache.lock().shrink(pfa)));
+        mem::slab::log_stats();
+    }
 
     // Setup interrupts
     unsafe {
@@ -214,6 +345,7 @@ pub extern "C" fn kernel_main(args: &KernelArgs) -> ! {
                     }
                 } else if let Some(ioapic) = entry.io_apic() {
                     // query the I/O APIC for some extra information
+                    unsafe { mem::direct::map_mmio(ioapic.address(), kmem::PAGE_SIZE) };
                     let regs = unsafe { IoApicRegisters::new(DIRECT_MAPPING.phys_to_virt(ioapic.address()).as_mut_ptr()) };
                     let redir_count = unsafe { regs.max_redirection_entries() };
                     let version = unsafe { regs.version() };
@@ -260,6 +392,10 @@ pub extern "C" fn kernel_main(args: &KernelArgs) -> ! {
         info!("  {:?}", ioa);
     }
 
+    for c in CPUS.read().iter() {
+        debug!("[kmem] CPU {:?} frame cache: {:?}", c.apic_id, mem::frames::cache_stats(c.apic_id));
+    }
+
     unsafe {
         let time = amd64::rtc::read_clock_consistent();
         info!("  Time: {:?}", time);
@@ -276,34 +412,67 @@ unsafe fn find_acpi_rsdp() -> Option<&'static acpi::Rsdp> {
     find_phys(0xE0000, 0xFFFFF).or(find_phys(0, 1024))
 }
 
+/// Return the memory used by the boot code, its page tables and its stack, as well as the
+/// multiboot information and the kernel symbol table loaded along with it, to the page frame
+/// allocator. Neither may be accessed afterwards.
+unsafe fn reclaim_boot_memory(args: &KernelArgs) {
+    let bootmem = PageFrameRegion::new_including(args.bootmem_start, args.bootmem_end);
+
+    // the multiboot information might share frames with the kernel or the modules
+    let mb2: &multiboot2::Multiboot2Info = &*DIRECT_MAPPING.phys_to_virt(args.multiboot_start).as_ptr();
+    let mut multiboot = RegionSet::new();
+    multiboot.insert(PageFrameRegion::new_including(args.multiboot_start, args.multiboot_end));
+    for region in symbols::boot_regions(mb2) {
+        multiboot.insert(region);
+    }
+    multiboot.remove(bootmem);
+    multiboot.remove(PageFrameRegion::new_including(args.kernel_start, args.kernel_end));
+    for m in mb2.modules() {
+        multiboot.remove(PageFrameRegion::new_including(m.mod_start(), m.mod_end()));
+    }
+
+    let mut pfa = mem::frames::global().lock();
+    pfa.free_region(bootmem);
+    for region in multiboot.iter() {
+        pfa.free_region(region);
+    }
+    debug!("[kmem] reclaimed {} frames of boot memory", bootmem.length() + multiboot.frame_count());
+}
+
 unsafe fn initialize_page_frame_table(kernel_args: &KernelArgs, mb2: &multiboot2::Multiboot2Info) -> PageFrameTable {
 
     // find memory map
-    let memory_map = mb2.memory_map().expect("Bootloader did not provide memory map.");
+    let memory_map = mb2.memory_map().expect("Bootloader did not provide memory map.").sanitized();
 
-    // compute start of physical heap
-    let heap_start = mb2.modules().map(|m| m.mod_end())
-        .chain(iter::once(kernel_args.kernel_end))
-        .chain(iter::once(kernel_args.multiboot_end))
-        .max().unwrap_or(PhysAddr(0));
+    // Set up the boot memory allocator from all available RAM regions, rounded down to page sizes,
+    // without the areas that are already in use.
+    let mut bootmem = BootMemAllocator::new();
+    memory_map.iter()
+        .filter(|r| r.is_available())
+        .map(|r| PageFrameRegion::new_included_in(r.base_addr(), r.base_addr() + r.length()))
+        .filter(|r| ! r.is_empty())
+        .for_each(|r| bootmem.add_available(r));
 
-    let heap_start_frame = PageFrame::next_above(heap_start);
+    bootmem.carve_out(PageFrameRegion::new_including(
+        kernel_args.multiboot_start, kernel_args.multiboot_end), PageFrameOwner::Kernel);
+    for m in mb2.modules() {
+        bootmem.carve_out(PageFrameRegion::new_including(m.mod_start(), m.mod_end()), PageFrameOwner::Module);
+    }
+    // the symbol table is copied once the heap is up
+    for region in symbols::boot_regions(mb2) {
+        bootmem.carve_out(region, PageFrameOwner::Kernel);
+    }
+    bootmem.carve_out(PageFrameRegion::new_including(
+        kernel_args.kernel_start, kernel_args.kernel_end), PageFrameOwner::Kernel);
+    bootmem.carve_out(PageFrameRegion::new_including(
+        kernel_args.bootmem_start, kernel_args.bootmem_end), PageFrameOwner::Kernel);
 
-    debug!("[kmem] first frame = {:p}", heap_start_frame.start_address());
+    debug!("[kmem] free boot memory: {:?} ({} frames)", bootmem.free_regions(), bootmem.free_regions().frame_count());
 
-    // Compute initial allocation regions: all available RAM regions, rounded down to page sizes,
-    // and above the important kernel data.
-    let available_regions = memory_map.regions()
+    // compute size required size of page frame table, which does not need to cover the reserved
+    // regions above the available memory
+    let page_frame_count = memory_map.iter()
         .filter(|r| r.is_available())
-        .map(|r| PageFrameRegion::new_included_in(r.base_addr(), r.base_addr() + r.length()))
-        .map(|r| PageFrameRegion {
-            start: cmp::max(r.start, heap_start_frame),
-            end: r.end
-        })
-        .filter(|r| ! r.is_empty());
-
-    // compute size required size of page frame table
-    let page_frame_count = memory_map.regions()
         .map(|r| PageFrame::next_above(r.end_addr()).0)
         .max().unwrap_or(0);
     
@@ -311,11 +480,10 @@ unsafe fn initialize_page_frame_table(kernel_args: &KernelArgs, mb2: &multiboot2
 
     debug!("[kmem] #pfa={} tblsize={} B", page_frame_count, page_frame_table_size);
 
-    // manually allocate page frame table
-    let page_frame_table_addr = available_regions.clone()
-        .find(|r| r.length() * kmem::PAGE_SIZE >= page_frame_table_size)
-        .map(|r| r.start.start_address())
+    let page_frame_table_region = bootmem.alloc(
+        (page_frame_table_size + kmem::PAGE_SIZE - 1) / kmem::PAGE_SIZE, 1)
         .expect("cannot allocate page frame table");
+    let page_frame_table_addr = page_frame_table_region.start.start_address();
 
     debug!("[kmem] tbladdr={:p}", page_frame_table_addr);
 
@@ -325,40 +493,59 @@ unsafe fn initialize_page_frame_table(kernel_args: &KernelArgs, mb2: &multiboot2
             page_frame_count
         );
 
-    // mark all BIOS reserved areas
-    memory_map.regions()
-        .filter(|r| ! r.is_available())
-        .map(|r| PageFrameRegion::new_including(r.base_addr(), r.base_addr() + r.length()))
-        .for_each(|r| page_frame_table.mark_reserved(r));
+    // mark all BIOS reserved areas, and quarantine defective ones for good
+    for r in memory_map.iter().filter(|r| ! r.is_available()) {
+        let mut region = PageFrameRegion::new_including(r.base_addr(), r.base_addr() + r.length());
+        region.end = region.end.min(PageFrame(page_frame_count));
+        if region.is_empty() {
+            continue;
+        }
+        if r.entry_type() == multiboot2::memmap::EntryType::DEFECTIVE {
+            page_frame_table.mark_defective(region);
+        } else {
+            page_frame_table.mark_reserved(region);
+        }
+    }
 
-    // mark page frame table as allocated    
-    page_frame_table.mark_allocated(PageFrameRegion::new_including(
-        page_frame_table_addr, page_frame_table_addr + page_frame_table_size));
+    // mark everything in use during boot (including the page frame table itself) as allocated
+    bootmem.finish(&mut page_frame_table);
 
-    // mark multiboot area as allocated
-    page_frame_table.mark_allocated(PageFrameRegion::new_including(
-        kernel_args.multiboot_start, kernel_args.multiboot_end));
-    for m in mb2.modules() {
-        page_frame_table.mark_allocated(PageFrameRegion::new_including(m.mod_start(), m.mod_end()));
-    }
+    page_frame_table
+}
 
-    // mark kernel area as allocated
-    page_frame_table.mark_allocated(PageFrameRegion::new_including(
-        kernel_args.kernel_start, kernel_args.kernel_end));
+/// Switch to the framebuffer console, if the pixel format of the framebuffer is supported.
+/// The framebuffer must be reachable through the direct mapping.
+unsafe fn init_framebuffer_console(tag: &multiboot2::framebuffer::FramebufferTag) {
+    match framebuffer::Framebuffer::from_tag(tag, DIRECT_MAPPING.phys_to_virt(tag.address())) {
+        Some(fb) => {
+            framebuffer::init(fb);
+            info!("Framebuffer console {}x{} at {:p}", tag.width(), tag.height(), tag.address());
+        },
+        None => info!("Framebuffer format {:?} not supported, keeping VGA text mode", tag.format()),
+    }
+}
 
-    // mark boot memory area as allocated
-    page_frame_table.mark_allocated(PageFrameRegion::new_including(
-        kernel_args.bootmem_start, kernel_args.bootmem_end));
+/// Whether the option was passed on the kernel command line.
+fn has_boot_option(mb2: &multiboot2::Multiboot2Info, option: &str) -> bool {
+    mb2.boot_cmd_line().map_or(false, |cmd_line| cmd_line.split_whitespace().any(|arg| arg == option))
+}
 
-    page_frame_table
+/// Weed out bad frames before the allocator hands them out, if requested on the command line.
+/// This needs the complete direct mapping for reaching all frames.
+unsafe fn run_memtest(mb2: &multiboot2::Multiboot2Info, page_frame_table: &mut PageFrameTable) {
+    if has_boot_option(mb2, "memtest") {
+        info!("[kmem] running memory test");
+        let defective = kmem::physical::memtest::test_free_frames(page_frame_table, &DIRECT_MAPPING);
+        info!("[kmem] memory test found {} defective frames", defective);
+    }
 }
 
 // TODO: write handlers for all CPU exceptions
 
 exception_handler_with_code! {
-    fn df_handler(_frame: &interrupts::InterruptFrame, error_code: u64) {
+    fn df_handler(stack_frame: &interrupts::InterruptFrame, error_code: u64) {
         unsafe { APIC.signal_eoi(); }
-        panic!("Double fault: {}", error_code);
+        panic!("Double fault: {} at {}", error_code, symbols::Symbolized(stack_frame.rip));
     }
 }
 
@@ -369,21 +556,30 @@ exception_handler_with_code! {
             asm!("mov $0, cr2" : "=r"(addr) : : : "intel");
         }
         unsafe { APIC.signal_eoi(); }
-        panic!("Page fault: {:05b} - {:p}\n{:X?}", error_code, VirtAddr(addr), stack_frame);
+        unsafe {
+            match kmem::paging::walk::translate(VirtAddr(addr)) {
+                Some(t) => error!("{:p} is mapped to {:p} ({:?}, {:?})", VirtAddr(addr), t.paddr, t.level, t.flags),
+                None => error!("{:p} is not mapped", VirtAddr(addr)),
+            }
+            if DUMP_ON_PAGE_FAULT.load(core::sync::atomic::Ordering::Relaxed) {
+                kmem::paging::walk::dump();
+            }
+        }
+        panic!("Page fault: {:05b} - {:p} at {}\n{:X?}", error_code, VirtAddr(addr), symbols::Symbolized(stack_frame.rip), stack_frame);
     }
 }
 
 exception_handler_with_code! {
     fn gpf_handler(stack_frame: &interrupts::InterruptFrame, error_code: u64) {
         unsafe { APIC.signal_eoi(); }
-        panic!("Protection fault: {:32b}\n{:X?}", error_code, stack_frame);
+        panic!("Protection fault: {:32b} at {}\n{:X?}", error_code, symbols::Symbolized(stack_frame.rip), stack_frame);
     }
 }
 
 interrupt_handler! {
-    fn div_by_zero_handler(_frame: &interrupts::InterruptFrame) {
+    fn div_by_zero_handler(stack_frame: &interrupts::InterruptFrame) {
         unsafe { APIC.signal_eoi(); }
-        panic!("division by zero");
+        panic!("division by zero at {}", symbols::Symbolized(stack_frame.rip));
     }
 }
 
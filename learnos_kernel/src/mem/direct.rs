@@ -0,0 +1,120 @@
+//! Building the direct mapping of physical memory.
+//!
+//! The boot code only maps the lowest 4 GiB. During boot, the kernel replaces that mapping by one
+//! that covers everything described by the memory map, as well as the MMIO regions it needs, up to
+//! the highest such address. Only RAM, i.e. available and ACPI reclaimable regions, is mapped as
+//! regular memory. Everything else, reserved regions as well as the holes in between, is mapped
+//! uncached, since that is where devices live.
+//!
+//! The new mapping is built in the PML4 slot following the direct mapping and then moved into place,
+//! so that the boot mapping stays usable while the new page tables are being allocated. From then on,
+//! page tables are edited through the direct mapping rather than the recursive mapping of the boot code.
+
+use amd64::{cr, Alignable, PhysAddr, VirtAddr};
+use kmem::PAGE_SIZE;
+use kmem::paging::{self, mapper, MapFlags};
+use kmem::paging::tables::PageTableEntry;
+use kmem::physical::alloc::PageFrameAllocator;
+use multiboot2::memmap::{EntryType, SanitizedMemoryMap};
+
+use crate::mem::frames;
+use crate::mem::layout::DIRECT_MAPPING;
+
+/// Whether the direct mapping has been built by the kernel. Also serializes extending it.
+static BUILT: spin::Mutex<bool> = spin::Mutex::new(false);
+
+/// Replace the direct mapping of the boot code by one covering the memory map and the given MMIO regions,
+/// each given by its start address and length. The page tables are taken from the given allocator,
+/// since the global one can only be set up once all memory is reachable.
+///
+/// Memory map regions beyond the end of the direct mapping are skipped, as long as they do not
+/// contain available memory. Firmware likes to put reserved regions at the very top of the physical
+/// address space, like the HyperTransport hole of AMD processors below 1 TiB.
+///
+/// # Panics
+///
+/// Panics if the direct mapping has already been built, or if available memory or one of the MMIO
+/// regions does not fit into it.
+pub unsafe fn init(memory_map: &SanitizedMemoryMap, mmio: &[(PhysAddr, usize)], pfa: &mut PageFrameAllocator) {
+    let mut built = BUILT.lock();
+    assert!(! *built, "direct mapping already built");
+
+    let limit = PhysAddr(DIRECT_MAPPING.size_in_bytes());
+    for region in memory_map.iter().filter(|r| r.end_addr() > limit) {
+        assert!(! region.is_available(), "available memory {:p} - {:p} exceeds the direct mapping",
+            region.base_addr(), region.end_addr());
+        warn!("[kmem] not direct mapping {:?} region {:p} - {:p} beyond {:p}",
+            region.entry_type(), region.base_addr(), region.end_addr(), limit);
+    }
+    for &(addr, length) in mmio {
+        assert!(addr + length <= limit, "MMIO region {:p} - {:p} exceeds the direct mapping", addr, addr + length);
+    }
+
+    let end = memory_map.iter().map(|r| r.end_addr())
+        .chain(mmio.iter().map(|&(addr, length)| addr + length))
+        .max().unwrap_or(PhysAddr(0))
+        .align_up(PAGE_SIZE)
+        .min(limit);
+
+    let staging = DIRECT_MAPPING.virtual_base() + DIRECT_MAPPING.size_in_bytes();
+    map_physical(staging, memory_map, PhysAddr(0), end, pfa);
+
+    // move the new tables into place, leaving the boot tables unused
+    let staging_entry: *mut PageTableEntry = paging::entry_at_level(3, staging).as_mut_ptr();
+    *paging::entry_at_level(3, DIRECT_MAPPING.virtual_base()).as_mut_ptr() = *staging_entry;
+    *staging_entry = PageTableEntry::new();
+    cr::write_cr3(cr::read_cr3());
+
+    DIRECT_MAPPING.set_mapped_bytes(end.0);
+    mapper::use_direct_mapping(&DIRECT_MAPPING);
+    *built = true;
+    debug!("[kmem] direct mapping covers {:p} - {:p}", PhysAddr(0), end);
+}
+
+/// Make sure that the MMIO region is part of the direct mapping, extending the mapping if necessary.
+///
+/// # Panics
+///
+/// Panics if the direct mapping has not been built yet.
+pub unsafe fn map_mmio(addr: PhysAddr, length: usize) {
+    let built = BUILT.lock();
+    assert!(*built, "direct mapping not built yet");
+
+    let mapped = PhysAddr(DIRECT_MAPPING.mapped_bytes());
+    let end = (addr + length).align_up(PAGE_SIZE);
+    if end > mapped {
+        let vaddr = DIRECT_MAPPING.virtual_base() + mapped.0;
+        frames::with_local(|pfa| map_segment(vaddr, mapped, end, MapFlags::MMIO, pfa));
+        DIRECT_MAPPING.set_mapped_bytes(end.0);
+        debug!("[kmem] direct mapping extended to {:p}", end);
+    }
+}
+
+/// Map the physical memory from `start` to `end` at `base` plus the physical address, using the
+/// memory map for telling RAM apart from MMIO.
+unsafe fn map_physical(base: VirtAddr, memory_map: &SanitizedMemoryMap, start: PhysAddr, end: PhysAddr, pfa: &mut PageFrameAllocator) {
+    let mut next = start;
+    for region in memory_map.iter().filter(|r| is_ram(r.entry_type())) {
+        let region_start = core::cmp::max(region.base_addr(), next);
+        let region_end = core::cmp::min(region.end_addr(), end);
+        if region_start < region_end {
+            map_segment(base + next.0, next, region_start, MapFlags::MMIO, pfa);
+            map_segment(base + region_start.0, region_start, region_end, MapFlags::KERNEL_DATA, pfa);
+            next = region_end;
+        }
+    }
+    map_segment(base + next.0, next, end, MapFlags::MMIO, pfa);
+}
+
+/// Whether memory map regions of the given type are backed by RAM, and can be mapped cacheable.
+fn is_ram(entry_type: EntryType) -> bool {
+    entry_type == EntryType::AVAILABLE || entry_type == EntryType::AVAILABLE_ACPI
+}
+
+/// Map the physical memory from `start` to `end` at `vaddr`.
+unsafe fn map_segment(vaddr: VirtAddr, start: PhysAddr, end: PhysAddr, flags: MapFlags, pfa: &mut PageFrameAllocator) {
+    if start < end {
+        paging::map_range(vaddr, start, end.0 - start.0, flags, pfa)
+            .unwrap_or_else(|err| panic!("cannot direct map {:p} - {:p}: {:?}", start, end, err));
+    }
+}
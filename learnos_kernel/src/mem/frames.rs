@@ -0,0 +1,84 @@
+//! The global page frame allocator, and the per-CPU page frame caches in front of it.
+//!
+//! Single page frames should be allocated through `alloc` and `free`, or the allocator
+//! passed to `with_local`. They are usually served from the cache of the current CPU
+//! without touching the lock of the global allocator.
+
+use core::mem;
+use core::ptr;
+use core::slice;
+
+use amd64::Alignable;
+use amd64::apic::{self, ApicId};
+use kmem::PAGE_SIZE;
+use kmem::physical::PageFrame;
+use kmem::physical::alloc::{PageFrameAllocator, BuddyPageFrameAllocator, CachedPageFrameAllocator, FrameCache, FrameCacheStats};
+use kmem::physical::mgmt::PageFrameOwner;
+
+use crate::mem::layout::DIRECT_MAPPING;
+use crate::smp::MAX_CPU_COUNT;
+
+/// The allocator managing all physical memory.
+static GLOBAL: spin::Once<spin::Mutex<BuddyPageFrameAllocator>> = spin::Once::new();
+
+/// The frame caches of all CPUs, indexed by local APIC ID.
+static CACHES: spin::Once<&'static [spin::Mutex<FrameCache>]> = spin::Once::new();
+
+/// Install the global page frame allocator and set up the per-CPU caches.
+pub fn init(pfa: BuddyPageFrameAllocator) {
+    let global = GLOBAL.call_once(|| spin::Mutex::new(pfa));
+    CACHES.call_once(|| unsafe {
+        // the caches are too large for being built on the boot stack, so they are placed in
+        // page frames taken from the allocator and initialized in place.
+        let size = MAX_CPU_COUNT * mem::size_of::<spin::Mutex<FrameCache>>();
+        let mut pfa = global.lock();
+        let region = pfa.alloc_region(size.align_up(PAGE_SIZE) / PAGE_SIZE)
+            .expect("cannot allocate page frame caches");
+        pfa.page_frame_table_mut().set_owner(region, PageFrameOwner::Kernel);
+        let caches: *mut spin::Mutex<FrameCache> = DIRECT_MAPPING.phys_to_virt(region.start.start_address()).as_mut_ptr();
+        for i in 0..MAX_CPU_COUNT {
+            ptr::write(caches.add(i), spin::Mutex::new(FrameCache::new()));
+        }
+        slice::from_raw_parts(caches, MAX_CPU_COUNT)
+    });
+}
+
+/// The global page frame allocator. Prefer `alloc` and `free` for single page frames.
+///
+/// # Panics
+///
+/// Panics when called before `init`.
+pub fn global() -> &'static spin::Mutex<BuddyPageFrameAllocator> {
+    GLOBAL.wait().expect("page frame allocator not initialized")
+}
+
+/// Allocate a single page frame from the cache of the current CPU.
+pub unsafe fn alloc() -> Option<PageFrame> {
+    local_cache().lock().alloc(global())
+}
+
+/// Free a single page frame into the cache of the current CPU.
+pub unsafe fn free(frame: PageFrame) {
+    local_cache().lock().free(frame, global())
+}
+
+/// Run a function with a page frame allocator that serves single frames from the
+/// cache of the current CPU. The cache stays locked while the function runs.
+pub fn with_local<F, R>(f: F) -> R where F: FnOnce(&mut PageFrameAllocator) -> R {
+    let mut cache = local_cache().lock();
+    let mut pfa = CachedPageFrameAllocator::new(&mut cache, global());
+    f(&mut pfa)
+}
+
+/// Statistics of the page frame cache of the CPU with the given APIC ID.
+pub fn cache_stats(apic_id: ApicId) -> FrameCacheStats {
+    caches()[apic_id.0 as usize].lock().stats()
+}
+
+fn caches() -> &'static [spin::Mutex<FrameCache>] {
+    CACHES.wait().expect("page frame caches not initialized")
+}
+
+fn local_cache() -> &'static spin::Mutex<FrameCache> {
+    &caches()[apic::local_apic_id().0 as usize]
+}
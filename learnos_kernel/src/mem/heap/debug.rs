@@ -0,0 +1,196 @@
+//! Debugging aids for the kernel heap, enabled by the `heap-debug` feature.
+//!
+//! Every allocation is surrounded by redzones filled with `REDZONE_BYTE`, which are checked when the
+//! allocation is freed. Fresh allocations are filled with `ALLOC_BYTE` and freed ones with `FREE_BYTE`,
+//! so that reads of uninitialized or freed memory produce conspicuous values. Live allocations are
+//! recorded along with the return addresses of their callers, as found by `symbols::backtrace`.
+//! Allocations that do not fit into the table get their front redzone filled with `UNTRACKED_BYTE`
+//! instead, so that freeing them can still be told apart from freeing a bogus pointer.
+//! The checks are only compiled in when building with `HEAP_DEBUG=1`, see the Makefile.
+
+use core::alloc::Layout;
+use core::fmt;
+use core::ptr;
+
+use amd64::Alignable;
+
+use super::KernelAllocator;
+use crate::symbols;
+
+/// Size of the redzone behind an allocation. The one in front is larger for alignments above this.
+const REDZONE_SIZE: usize = 16;
+
+/// Fill pattern of the redzones.
+const REDZONE_BYTE: u8 = 0xFD;
+
+/// Fill pattern of the front redzone of allocations missing from the table of live allocations.
+const UNTRACKED_BYTE: u8 = 0xFE;
+
+/// Fill pattern of freshly allocated memory.
+const ALLOC_BYTE: u8 = 0xCD;
+
+/// Fill pattern of freed memory.
+const FREE_BYTE: u8 = 0xDD;
+
+/// Maximum number of live allocations that are tracked.
+const LIVE_CAPACITY: usize = 1024;
+
+/// Number of return addresses recorded per allocation, starting with the innermost one.
+const CALLER_DEPTH: usize = 6;
+
+#[derive(Clone, Copy)]
+struct LiveAllocation {
+    /// The address handed out by the allocator, zero for unused entries.
+    ptr: usize,
+    size: usize,
+    callers: [usize; CALLER_DEPTH],
+}
+
+impl LiveAllocation {
+    const UNUSED: LiveAllocation = LiveAllocation { ptr: 0, size: 0, callers: [0; CALLER_DEPTH] };
+}
+
+/// The table of live allocations.
+pub struct LiveAllocations {
+    entries: [LiveAllocation; LIVE_CAPACITY],
+    /// Number of allocations that were not recorded because the table was full.
+    untracked: usize,
+}
+
+impl LiveAllocations {
+    pub const fn new() -> LiveAllocations {
+        LiveAllocations {
+            entries: [LiveAllocation::UNUSED; LIVE_CAPACITY],
+            untracked: 0,
+        }
+    }
+
+    /// Record an allocation, returning `false` if the table is full.
+    fn insert(&mut self, allocation: LiveAllocation) -> bool {
+        match self.entries.iter_mut().find(|entry| entry.ptr == 0) {
+            Some(entry) => {
+                *entry = allocation;
+                true
+            },
+            None => {
+                if self.untracked == 0 {
+                    warn!("[kmem] more than {} live heap allocations, callers are no longer recorded", LIVE_CAPACITY);
+                }
+                self.untracked += 1;
+                false
+            },
+        }
+    }
+
+    fn remove(&mut self, ptr: usize) -> Option<LiveAllocation> {
+        let entry = self.entries.iter_mut().find(|entry| entry.ptr == ptr)?;
+        let allocation = *entry;
+        *entry = LiveAllocation::UNUSED;
+        Some(allocation)
+    }
+}
+
+impl KernelAllocator {
+    /// Log all live allocations together with their callers to the serial log.
+    pub fn dump_live_allocations(&self) {
+        let live = self.live.lock();
+        debug!("[kmem] live heap allocations:");
+        for entry in live.entries.iter().filter(|entry| entry.ptr != 0) {
+            debug!("[kmem]   {:#x}, {} bytes, from {}", entry.ptr, entry.size, Callers(&entry.callers));
+        }
+        if live.untracked > 0 {
+            debug!("[kmem]   {} allocations were not tracked", live.untracked);
+        }
+    }
+
+    /// Allocate a block with redzones around it and record it in the table of live allocations.
+    pub(super) unsafe fn alloc_tracked(&self, layout: Layout) -> *mut u8 {
+        let outer = match outer_layout(layout) {
+            Some(outer) => outer,
+            None => return ptr::null_mut(),
+        };
+        let block = self.alloc_block(outer);
+        if block.is_null() {
+            return block;
+        }
+        let front = front_size(layout);
+        let ptr = block.add(front);
+        ptr::write_bytes(block, REDZONE_BYTE, front);
+        ptr::write_bytes(ptr, ALLOC_BYTE, layout.size());
+        ptr::write_bytes(ptr.add(layout.size()), REDZONE_BYTE, REDZONE_SIZE);
+        let mut callers = [0; CALLER_DEPTH];
+        let mut slots = callers.iter_mut();
+        symbols::backtrace(|addr| if let Some(slot) = slots.next() { *slot = addr });
+        let tracked = self.live.lock().insert(LiveAllocation {
+            ptr: ptr as usize,
+            size: layout.size(),
+            callers: callers,
+        });
+        if ! tracked {
+            ptr::write_bytes(block, UNTRACKED_BYTE, front);
+        }
+        ptr
+    }
+
+    /// Check the redzones of a block allocated by `alloc_tracked`, then poison and free it.
+    pub(super) unsafe fn dealloc_checked(&self, ptr: *mut u8, layout: Layout) {
+        let front = front_size(layout);
+        let block = ptr.sub(front);
+        let allocation = self.live.lock().remove(ptr as usize);
+        let (callers, front_byte) = match allocation {
+            Some(allocation) => {
+                assert!(allocation.size == layout.size(), "heap: {:p} allocated with {} bytes, freed with {} bytes, from {}",
+                    ptr, allocation.size, layout.size(), Callers(&allocation.callers));
+                (allocation.callers, REDZONE_BYTE)
+            },
+            None if find_damage(block, front, UNTRACKED_BYTE).is_none() => ([0; CALLER_DEPTH], UNTRACKED_BYTE),
+            None => panic!("heap: {:p} is freed, but not allocated", ptr),
+        };
+
+        let damage = find_damage(block, front, front_byte)
+            .or_else(|| find_damage(ptr.add(layout.size()), REDZONE_SIZE, REDZONE_BYTE).map(|offset| front + layout.size() + offset));
+        if let Some(offset) = damage {
+            panic!("heap: redzone of {:p} ({} bytes) overwritten at {:p}, allocated from {}",
+                ptr, layout.size(), block.add(offset), Callers(&callers));
+        }
+
+        let outer = outer_layout(layout).expect("freed layout was never allocated");
+        ptr::write_bytes(block, FREE_BYTE, outer.size());
+        self.dealloc_block(block, outer);
+    }
+}
+
+/// Size of the redzone in front of an allocation, which keeps the allocation aligned.
+fn front_size(layout: Layout) -> usize {
+    REDZONE_SIZE.align_up(layout.align())
+}
+
+/// The layout of an allocation including its redzones.
+fn outer_layout(layout: Layout) -> Option<Layout> {
+    let size = front_size(layout).checked_add(layout.size())?.checked_add(REDZONE_SIZE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Return the offset of the first byte of a redzone that does not hold the given fill pattern.
+unsafe fn find_damage(redzone: *const u8, size: usize, fill: u8) -> Option<usize> {
+    (0..size).find(|&offset| *redzone.add(offset) != fill)
+}
+
+/// Formats recorded return addresses, omitting the unused ones.
+struct Callers<'a>(&'a [usize; CALLER_DEPTH]);
+
+impl<'a> fmt::Display for Callers<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut addresses = self.0.iter().filter(|&&addr| addr != 0);
+        match addresses.next() {
+            None => write!(f, "unknown callers"),
+            Some(first) => {
+                write!(f, "{:#x}", first)?;
+                for addr in addresses {
+                    write!(f, " <- {:#x}", addr)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
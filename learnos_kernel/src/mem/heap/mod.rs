@@ -0,0 +1,115 @@
+//! The kernel heap, serving the allocations of the `alloc` crate.
+//!
+//! The heap lives in its own area of the kernel half and starts out empty. Whenever an allocation
+//! does not fit, page frames are mapped at its top. The heap never shrinks.
+//!
+//! With the `heap-debug` feature, allocations are additionally checked for overflows and
+//! tracked, see the `debug` module.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+use amd64::Alignable;
+use kmem::PAGE_SIZE;
+use kmem::heap::Heap;
+use kmem::paging::{self, MapFlags, MappingLevel};
+use kmem::physical::mgmt::PageFrameOwner;
+
+use crate::mem::{frames, slab};
+use crate::mem::layout::{KERNEL_HEAP_START, KERNEL_HEAP_END};
+
+#[cfg(feature = "heap-debug")]
+mod debug;
+
+/// Minimum number of bytes the heap grows by at once.
+const HEAP_GROWTH: usize = 64 * 1024;
+
+/// The global allocator of the kernel, registered in the crate root.
+pub struct KernelAllocator {
+    heap: spin::Mutex<Heap>,
+    #[cfg(feature = "heap-debug")]
+    live: spin::Mutex<debug::LiveAllocations>,
+}
+
+impl KernelAllocator {
+    pub const fn new() -> KernelAllocator {
+        KernelAllocator {
+            heap: spin::Mutex::new(Heap::empty(KERNEL_HEAP_START)),
+            #[cfg(feature = "heap-debug")]
+            live: spin::Mutex::new(debug::LiveAllocations::new()),
+        }
+    }
+
+    /// Return the size of the heap and the number of bytes currently not allocated.
+    pub fn stats(&self) -> (usize, usize) {
+        let heap = self.heap.lock();
+        (heap.size(), heap.free_bytes())
+    }
+
+    /// Allocate a block from the heap, growing it as needed. Returns null if memory is exhausted.
+    unsafe fn alloc_block(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.heap.lock();
+        loop {
+            if let Some(ptr) = heap.alloc(layout) {
+                return ptr.as_ptr();
+            }
+            // when memory is short, the slab caches might have some to spare
+            if ! grow(&mut heap, layout) && slab::reclaim() == 0 {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().dealloc(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_block(layout)
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc_block(ptr, layout)
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_tracked(layout)
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc_checked(ptr, layout)
+    }
+}
+
+/// Map enough memory at the top of the heap for satisfying an allocation with the given layout.
+/// Returns `false` if nothing could be mapped, because the heap area or the memory is exhausted.
+unsafe fn grow(heap: &mut Heap, layout: Layout) -> bool {
+    let top = heap.top();
+    let bytes = (layout.size() + layout.align()).max(HEAP_GROWTH).align_up(PAGE_SIZE)
+        .min(KERNEL_HEAP_END.0 - top.0);
+    let mapped = frames::with_local(|pfa| {
+        for offset in (0..bytes).step_by(PAGE_SIZE) {
+            let frame = match pfa.alloc() {
+                Some(frame) => frame,
+                None => return offset,
+            };
+            pfa.set_frame_owner(frame, PageFrameOwner::KernelHeap);
+            if paging::mmap(top + offset, frame.start_address(), MappingLevel::Page4K, MapFlags::KERNEL_DATA, pfa).is_err() {
+                pfa.free(frame);
+                return offset;
+            }
+        }
+        bytes
+    });
+    // even a partial extension might be enough for the allocation
+    if mapped > 0 {
+        heap.extend(mapped);
+    }
+    mapped > 0
+}
@@ -1,15 +1,22 @@
 //! Hardcoded memory layout of the kernel.
 //! 
-//! The kernel expects that the lowest 2 GiB of physical memory are mapped to `0xFFFFFFFF_80000000`.
+//! The boot code maps the lowest 2 GiB of physical memory to `0xFFFFFFFF_80000000`. Once the kernel
+//! has built its own page tables, only the sections of the kernel binary remain mapped there.
 //! 
 //! # Virtual memory layout
 //! 
-//! - `0xFFFF_8000_0000_0000` 509th PML4 entry, used for direct mapping physical memory
-//! - `0xFFFF_FF00_0000_0000` 510th PML4 entry, used for recursive mapping
+//! - `0xFFFF_8000_0000_0000` 256th PML4 entry, used for direct mapping physical memory
+//! - `0xFFFF_8080_0000_0000` 257th PML4 entry, used for building the direct mapping during boot
+//! - `0xFFFF_FF00_0000_0000` 510th PML4 entry, used for recursive mapping by the boot page tables,
+//!   unused once the kernel page tables are active
 //! - `0xFFFF_FF80_0000_0000` 511th PML4 entry, reserved for kernel usage
+//!   - `0xFFFF_FF80_0000_0000` vmalloc area for virtually contiguous kernel buffers
+//!   - `0xFFFF_FFFE_0000_0000` kernel heap, growing upwards
+//!   - `0xFFFF_FFFF_7FFF_0000` stack of the boot processor, with an unmapped guard page below
 //!   - `0xFFFF_FFFF_8000_0000` mapped to lowest 2 GiB, contains the kernel binary
 
 use amd64::{PhysAddr, VirtAddr};
+use kmem::paging::MapFlags;
 use kmem::paging::direct::DirectMapping;
 
 /// The virtual address where the kernel reserved area begins (highest 2 GiB)
@@ -18,12 +25,79 @@ pub const KERNEL_VIRTUAL_BASE: VirtAddr = VirtAddr(0xFFFFFFFF80000000);
 /// The highest physical address that's mapped in the kernel area.
 pub const LOW_PHYS_MAX: PhysAddr = PhysAddr(0x0000000080000000);
 
-/// Direct mapping for the first 512 GB of physical memory
-pub const DIRECT_MAPPING: DirectMapping = DirectMapping::new(VirtAddr(0xFFFF_8000_0000_0000), PhysAddr(0), 1 << 39);
+/// The amount of physical memory that is guaranteed to be direct mapped by the boot code.
+pub const BOOT_DIRECT_MAPPING_SIZE: usize = 4 << 30;
+
+/// Direct mapping for up to 512 GB of physical memory. Initially, only the part mapped by the
+/// boot code is available, until `mem::direct::init` maps the memory that is actually present.
+pub static DIRECT_MAPPING: DirectMapping = DirectMapping::partial(
+    VirtAddr(0xFFFF_8000_0000_0000), PhysAddr(0), 1 << 39, BOOT_DIRECT_MAPPING_SIZE);
+
+/// Start of the area where `mem::vmalloc` places its buffers.
+pub const VMALLOC_START: VirtAddr = VirtAddr(0xFFFF_FF80_0000_0000);
+
+/// End of the vmalloc area, where the kernel heap begins.
+pub const VMALLOC_END: VirtAddr = KERNEL_HEAP_START;
+
+/// Start of the area backing the kernel heap, see `mem::heap`.
+pub const KERNEL_HEAP_START: VirtAddr = VirtAddr(0xFFFF_FFFE_0000_0000);
+
+/// End of the kernel heap area, leaving the last 4 GiB to the kernel binary and the boot processor stack.
+pub const KERNEL_HEAP_END: VirtAddr = VirtAddr(0xFFFF_FFFF_0000_0000);
+
+/// The top of the stack the boot processor uses once the kernel page tables are active.
+pub const BSP_STACK_TOP: VirtAddr = KERNEL_VIRTUAL_BASE;
+
+/// The size of the stack of the boot processor.
+pub const BSP_STACK_SIZE: usize = 64 * 1024;
+
+/// A section of the kernel binary, as laid out by the linker script.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelSection {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    /// The permissions the section is mapped with.
+    pub flags: MapFlags,
+}
+
+extern "C" {
+    static kernel_text_start: u8;
+    static kernel_text_end: u8;
+    static kernel_rodata_start: u8;
+    static kernel_rodata_end: u8;
+    static kernel_data_start: u8;
+    static kernel_data_end: u8;
+    static kernel_bss_start: u8;
+    static kernel_bss_end: u8;
+}
+
+/// The page aligned sections of the kernel binary, in ascending order.
+pub fn kernel_sections() -> [KernelSection; 4] {
+    let section = |name, start: &u8, end: &u8, flags| KernelSection {
+        name: name,
+        start: VirtAddr(start as *const u8 as usize),
+        end: VirtAddr(end as *const u8 as usize),
+        flags: flags,
+    };
+    unsafe {[
+        section(".text", &kernel_text_start, &kernel_text_end, MapFlags::EXECUTABLE),
+        section(".rodata", &kernel_rodata_start, &kernel_rodata_end, MapFlags::empty()),
+        section(".data", &kernel_data_start, &kernel_data_end, MapFlags::KERNEL_DATA),
+        section(".bss", &kernel_bss_start, &kernel_bss_end, MapFlags::KERNEL_DATA),
+    ]}
+}
 
 /// Map a physical address inside the physical kernel code region to
 /// its corresponding virtual address in the highest two 2 GiB.
 pub fn kernel_code_mapping(phys: PhysAddr) -> VirtAddr {
     assert!(phys < LOW_PHYS_MAX);
     VirtAddr(phys.0 + KERNEL_VIRTUAL_BASE.0)
+}
+
+/// Map a virtual address in the highest 2 GiB to the physical address it is mapped to
+/// by the kernel code mapping.
+pub fn kernel_code_physical(virt: VirtAddr) -> PhysAddr {
+    assert!(virt >= KERNEL_VIRTUAL_BASE);
+    PhysAddr(virt.0 - KERNEL_VIRTUAL_BASE.0)
 }
\ No newline at end of file
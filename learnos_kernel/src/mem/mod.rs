@@ -1 +1,7 @@
-pub mod layout;
\ No newline at end of file
+pub mod direct;
+pub mod frames;
+pub mod heap;
+pub mod layout;
+pub mod slab;
+pub mod space;
+pub mod vmalloc;
@@ -0,0 +1,64 @@
+//! Slab caches shared by all CPUs, and the registry used for reclaiming their empty slabs.
+//!
+//! A cache is a static `KernelCache`, e.g.
+//! `static TASKS: KernelCache<Task> = spin::Mutex::new(SlabCache::new("task", &DIRECT_MAPPING))`,
+//! that is `register`ed once so that `reclaim` can shrink it when memory runs short.
+
+use core::ptr::NonNull;
+
+use kmem::slab::{ObjectCache, SlabCache};
+
+use crate::mem::frames;
+
+/// Maximum number of caches that can be registered.
+pub const MAX_SLAB_CACHES: usize = 32;
+
+/// A slab cache shared by all CPUs.
+pub type KernelCache<T> = spin::Mutex<SlabCache<T>>;
+
+/// The caches considered by `reclaim`.
+static REGISTRY: spin::Mutex<[Option<&'static spin::Mutex<ObjectCache + Send>>; MAX_SLAB_CACHES]> =
+    spin::Mutex::new([None; MAX_SLAB_CACHES]);
+
+/// Make the empty slabs of a cache available to `reclaim`.
+///
+/// # Panics
+///
+/// Panics if `MAX_SLAB_CACHES` caches have been registered already.
+pub fn register(cache: &'static spin::Mutex<ObjectCache + Send>) {
+    let mut registry = REGISTRY.lock();
+    let slot = registry.iter_mut().find(|slot| slot.is_none()).expect("too many slab caches");
+    *slot = Some(cache);
+}
+
+/// Allocate an object from a cache, taking new slabs from the page frame cache of the current CPU.
+pub unsafe fn alloc<T>(cache: &KernelCache<T>) -> Option<NonNull<T>> {
+    frames::with_local(|pfa| cache.lock().alloc(pfa))
+}
+
+/// Return an object to the cache it was allocated from.
+pub unsafe fn free<T>(cache: &KernelCache<T>, object: NonNull<T>) {
+    cache.lock().free(object)
+}
+
+/// Give the empty slabs of all registered caches back to the page frame allocator,
+/// returning the number of freed frames. Caches that are currently locked are skipped.
+pub fn reclaim() -> usize {
+    let registry = REGISTRY.lock();
+    frames::with_local(|pfa| {
+        registry.iter().filter_map(|slot| *slot)
+            .filter_map(|cache| cache.try_lock())
+            .map(|mut cache| unsafe { cache.shrink(pfa) })
+            .sum()
+    })
+}
+
+/// Log the statistics of all registered caches.
+pub fn log_stats() {
+    for cache in REGISTRY.lock().iter().filter_map(|slot| *slot) {
+        let cache = cache.lock();
+        let stats = cache.stats();
+        debug!("[kmem] slab {}: {} slabs ({} empty), {} objects in use, {} allocations",
+            cache.name(), stats.slabs, stats.empty_slabs, stats.objects_in_use, stats.allocations);
+    }
+}
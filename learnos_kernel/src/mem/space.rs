@@ -0,0 +1,84 @@
+//! The kernel address space, replacing the page tables set up by the boot code.
+//!
+//! The boot code identity maps low memory and maps the lowest 2 GiB read-write to the kernel area.
+//! The kernel page tables instead only contain the direct mapping, the sections of the kernel
+//! binary with their own permissions (code is never writable, data never executable), and a fresh
+//! stack for the boot processor. Since the boot stack is not part of them, the stack is switched
+//! along with the page tables. The recursive mapping of the boot code is not carried over either.
+//! All kernel half PML4 entries get a PDP table up front, at the cost of 1 MiB, so that the kernel
+//! half stays shared by the address spaces created later, whichever of them extends it.
+
+use amd64::{cr, Alignable, PhysAddr, VirtAddr};
+use kmem::PAGE_SIZE;
+use kmem::paging::MapFlags;
+use kmem::paging::mapper::Mapper;
+use kmem::paging::space::AddressSpace;
+use kmem::paging::tables::PageTableEntry;
+use kmem::physical::alloc::PageFrameAllocator;
+use kmem::physical::mgmt::PageFrameOwner;
+
+use crate::mem::frames;
+use crate::mem::layout::{self, DIRECT_MAPPING, KERNEL_VIRTUAL_BASE, BSP_STACK_TOP, BSP_STACK_SIZE};
+
+/// Build the kernel page tables, with the kernel binary occupying the given physical memory,
+/// and switch to them. Execution continues by calling `cont` on the stack of the boot processor.
+///
+/// # Safety
+///
+/// Nothing may refer to the boot stack, the identity mapping or the recursive mapping anymore,
+/// since they are gone once `cont` is called. The direct mapping must already be built.
+pub unsafe fn enter(kernel_start: PhysAddr, kernel_end: PhysAddr, cont: extern "C" fn() -> !) -> ! {
+    let pml4 = {
+        let mut pfa = frames::global().lock();
+        let space = AddressSpace::new(&DIRECT_MAPPING, &mut *pfa).expect("cannot allocate kernel PML4");
+
+        // the kernel area is rebuilt from scratch, without the mappings of the boot code
+        *space.entry(3, KERNEL_VIRTUAL_BASE) = PageTableEntry::new();
+        map_kernel(&space, layout::kernel_code_mapping(kernel_start), layout::kernel_code_mapping(kernel_end), &mut *pfa);
+
+        let stack = pfa.alloc_region(BSP_STACK_SIZE / PAGE_SIZE).expect("cannot allocate kernel stack");
+        pfa.page_frame_table_mut().set_owner(stack, PageFrameOwner::Kernel);
+        space.map_range(BSP_STACK_TOP - BSP_STACK_SIZE, stack.start.start_address(), BSP_STACK_SIZE,
+            MapFlags::KERNEL_DATA, &mut *pfa).expect("cannot map kernel stack");
+        space.populate_kernel_half(&mut *pfa).expect("cannot allocate kernel PDP tables");
+
+        space.pml4().start_address()
+    };
+
+    cr::enable_write_protect();
+    switch(pml4, BSP_STACK_TOP, cont)
+}
+
+/// Map the sections of the kernel binary between `start` and `end` with their permissions.
+/// Anything in between, such as sections not mentioned in the linker script, is mapped read-only and not executable.
+unsafe fn map_kernel(space: &AddressSpace, start: VirtAddr, end: VirtAddr, pfa: &mut PageFrameAllocator) {
+    let mut next = start;
+    for section in layout::kernel_sections().iter().filter(|s| s.start < s.end) {
+        map_kernel_range(space, next, section.start, MapFlags::empty(), pfa);
+        map_kernel_range(space, section.start, section.end, section.flags, pfa);
+        debug!("[kmem] {:<7} {:p} - {:p} {:?}", section.name, section.start, section.end, section.flags);
+        next = section.end;
+    }
+    map_kernel_range(space, next, end, MapFlags::empty(), pfa);
+}
+
+/// Map the part of the kernel binary from `start` to `end`, widened to page boundaries.
+unsafe fn map_kernel_range(space: &AddressSpace, start: VirtAddr, end: VirtAddr, flags: MapFlags, pfa: &mut PageFrameAllocator) {
+    let (start, end) = (start.align_down(PAGE_SIZE), end.align_up(PAGE_SIZE));
+    if start < end {
+        space.map_range(start, layout::kernel_code_physical(start), end.0 - start.0, flags, pfa)
+            .unwrap_or_else(|err| panic!("cannot map kernel {:p} - {:p}: {:?}", start, end, err));
+    }
+}
+
+/// Load the page tables, switch to the new stack and call `cont` on it.
+/// The old stack must not be touched in between, since it is no longer mapped.
+#[inline(always)]
+unsafe fn switch(pml4: PhysAddr, stack_top: VirtAddr, cont: extern "C" fn() -> !) -> ! {
+    asm!("mov cr3, $0
+          mov rsp, $1
+          xor rbp, rbp
+          call $2"
+        : : "r"(pml4.0), "r"(stack_top.0), "r"(cont) : "memory" : "intel", "volatile");
+    core::intrinsics::unreachable()
+}
@@ -0,0 +1,63 @@
+//! Virtually contiguous kernel buffers, backed by page frames from anywhere in physical memory.
+//!
+//! The addresses are taken from the vmalloc area of the kernel half, which is shared by all
+//! address spaces. Each buffer is surrounded by unmapped guard pages, so that running over
+//! either end faults instead of silently corrupting a neighbouring buffer.
+
+use kmem::paging::{self, MapFlags, MappingLevel};
+use kmem::paging::vrange::{VirtRange, VirtRangeAllocator};
+use kmem::physical::PageFrame;
+use kmem::physical::mgmt::PageFrameOwner;
+
+use crate::mem::frames;
+use crate::mem::layout::{VMALLOC_START, VMALLOC_END};
+
+/// Number of unmapped pages on either side of a buffer.
+const GUARD_PAGES: usize = 1;
+
+/// The address space of the vmalloc area.
+static RANGES: spin::Once<spin::Mutex<VirtRangeAllocator>> = spin::Once::new();
+
+/// Allocate a buffer of `page_count` pages, mapped with the given flags.
+/// Returns `None` if there is not enough address space or memory left.
+pub unsafe fn vmalloc(page_count: usize, flags: MapFlags) -> Option<VirtRange> {
+    let range = ranges().lock().alloc(page_count, GUARD_PAGES)?;
+    for i in 0..page_count {
+        let mapped = frames::with_local(|pfa| {
+            let frame = pfa.alloc()?;
+            pfa.set_frame_owner(frame, PageFrameOwner::KernelHeap);
+            match paging::mmap(range.page(i), frame.start_address(), MappingLevel::Page4K, flags, pfa) {
+                Ok(()) => Some(()),
+                Err(_) => {
+                    pfa.free(frame);
+                    None
+                }
+            }
+        });
+        if mapped.is_none() {
+            release(range, i);
+            return None;
+        }
+    }
+    Some(range)
+}
+
+/// Unmap a buffer allocated by `vmalloc` and free its page frames and addresses.
+pub unsafe fn vfree(range: VirtRange) {
+    release(range, range.page_count());
+}
+
+/// Free the first `mapped_pages` pages of the range along with their frames, then the range itself.
+unsafe fn release(range: VirtRange, mapped_pages: usize) {
+    frames::with_local(|pfa| {
+        for i in 0..mapped_pages {
+            let (paddr, _) = paging::unmmap(range.page(i), pfa).expect("vmalloc page not mapped");
+            pfa.free(PageFrame::including(paddr));
+        }
+    });
+    ranges().lock().free(range);
+}
+
+fn ranges() -> &'static spin::Mutex<VirtRangeAllocator> {
+    RANGES.call_once(|| spin::Mutex::new(VirtRangeAllocator::new(VMALLOC_START, VMALLOC_END)))
+}
@@ -6,9 +6,13 @@ use core::fmt::{Write};
 #[cfg(not(test))]
 use crate::vga;
 #[cfg(not(test))]
+use crate::framebuffer;
+#[cfg(not(test))]
 use amd64::io;
 #[cfg(not(test))]
 use crate::mem::layout;
+#[cfg(not(test))]
+use crate::symbols;
 
 #[cfg(not(test))]
 #[alloc_error_handler]
@@ -19,8 +23,9 @@ fn foo(layout: core::alloc::Layout) -> ! {
 #[panic_handler]
 #[cfg(not(test))]
 fn panic(panic_info: &PanicInfo) -> ! {
-    fn write_panic(writer: &mut vga::Writer, panic_info: &PanicInfo) {
+    fn write_panic(writer: &mut Write, panic_info: &PanicInfo) {
         writeln!(writer, "{}", panic_info);
+        symbols::backtrace(|addr| { writeln!(writer, "  at {}", symbols::Symbolized(addr)); });
     }
 
     fn extreme_panic(panic_info: &PanicInfo) {
@@ -33,19 +38,36 @@ fn panic(panic_info: &PanicInfo) -> ! {
         write_panic(&mut temp_console, panic_info);
     }
 
-    // try to grab the global VGA writer first, so that the panic doesn't erase previously logged info.
+    fn extreme_framebuffer_panic(panic_info: &PanicInfo) {
+        // Same as above, but for when the screen is in a graphics mode.
+        if let Some(mut temp_console) = unsafe { framebuffer::emergency_console(vga::Color::White, vga::Color::Red) } {
+            write_panic(&mut temp_console, panic_info);
+        }
+    }
+
+    // try to grab the global console first, so that the panic doesn't erase previously logged info.
     // That info could be very valuable for debugging.
-    match vga::GLOBAL_WRITER.try_lock() {
-        None => extreme_panic(panic_info),
-        Some(mut optwriter) => match *optwriter {
+    if framebuffer::is_initialized() {
+        match framebuffer::GLOBAL_CONSOLE.try_lock() {
+            None => extreme_framebuffer_panic(panic_info),
+            Some(mut optconsole) => match *optconsole {
+                None => extreme_framebuffer_panic(panic_info),
+                Some(ref mut console) => write_panic(console, panic_info)
+            }
+        };
+    } else {
+        match vga::GLOBAL_WRITER.try_lock() {
             None => extreme_panic(panic_info),
-            Some(ref mut writer) => write_panic(writer, panic_info)
-        }
-    };
+            Some(mut optwriter) => match *optwriter {
+                None => extreme_panic(panic_info),
+                Some(ref mut writer) => write_panic(writer, panic_info)
+            }
+        };
+    }
 
     // Also dump the panic to the serial port.
     let mut com1 = unsafe { io::com::SerialPort::new(io::com::COM1_ADDR) };
-    writeln!(com1, "{}", panic_info);
+    write_panic(&mut com1, panic_info);
 
     unsafe {
         amd64::interrupts::disable();
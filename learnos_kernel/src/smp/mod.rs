@@ -2,6 +2,8 @@ use core::iter;
 use core::mem;
 use core::ops;
 
+use alloc::vec::Vec;
+
 use amd64::PhysAddr;
 use amd64::apic::{ApicId, Lint, Polarity, TriggerMode};
 use amd64::ioapic::IoApicId;
@@ -61,44 +63,40 @@ pub struct IrqInfo {
 
 macro_rules! info_table {
     ($name:ident, $entry_type:ty, $entry_count:expr, { $($extra_fn:tt)* }) => {
-        /// A table for keeping track of all (at most 256) CPUs in the system.
+        /// A table for keeping track of the entries found in the system, up to the architectural limit.
         pub struct $name {
-            entries: [mem::ManuallyDrop<$entry_type>; $entry_count],
-            count: usize,
+            entries: Vec<$entry_type>,
         }
 
         impl $name {
             pub fn new() -> $name {
                 $name {
-                    entries: unsafe { mem::uninitialized() },
-                    count: 0
+                    entries: Vec::new(),
                 }
             }
 
             /// Return the number of entries.
             pub fn count(&self) -> usize {
-                self.count
+                self.entries.len()
             }
 
             /// Insert an entry into the table and return its internal ID.
             ///
             /// # Panics
             ///
-            /// Panics when trying to insert more than 256 entries.
+            /// Panics when trying to insert more entries than the architectural limit.
             pub fn insert(&mut self, entry: $entry_type) -> usize {
-                assert!(self.count < $entry_count, "too many entries");
-                let index = self.count;
-                self.count += 1;
-                self.entries[index] = mem::ManuallyDrop::new(entry);
-                index
+                assert!(self.entries.len() < $entry_count, "too many entries");
+                self.entries.push(entry);
+                self.entries.len() - 1
             }
 
             pub fn iter(&self) -> impl Iterator<Item=&$entry_type> {
-                self.entries[0..self.count].iter().map(|c| &**c)
+                self.entries.iter()
             }
 
             pub fn iter_mut(&mut self) -> impl Iterator<Item=&mut $entry_type> {
-                self.entries[0..self.count].iter_mut().map(|c| &mut **c)
+                self.entries.iter_mut()
             }
 
             $($extra_fn)*
@@ -107,48 +105,30 @@ macro_rules! info_table {
         impl iter::FromIterator<$entry_type> for $name {
             fn from_iter<T: IntoIterator<Item = $entry_type>>(iter: T) -> Self {
                 let mut table = $name::new();
-                for entry in iter.into_iter().take(256) {
-                    table.insert(entry);
-                }
+                table.extend(iter);
                 table
             }
         }
 
         impl iter::Extend<$entry_type> for $name {
             fn extend<T: IntoIterator<Item = $entry_type>>(&mut self, iter: T) {
-                for entry in iter.into_iter().take(256) {
+                for entry in iter.into_iter().take($entry_count) {
                     self.insert(entry);
                 }
             }
         }
 
-
         impl ops::Index<u8> for $name {
             type Output = $entry_type;
 
             fn index(&self, idx: u8) -> &$entry_type {
-                let uidx = idx as usize;
-                assert!(uidx < self.count(), "index out of range");
-                &*self.entries[uidx]
+                &self.entries[idx as usize]
             }
         }
 
         impl ops::IndexMut<u8> for $name {
             fn index_mut(&mut self, idx: u8) -> &mut $entry_type {
-                let uidx = idx as usize;
-                assert!(uidx < self.count(), "tindex out of range");
-                &mut *self.entries[uidx]
-            }
-        }
-
-        impl Drop for $name {
-            fn drop(&mut self) {
-                while self.count > 0 {
-                    self.count -= 1;
-                    unsafe {
-                        mem::ManuallyDrop::drop(&mut self.entries[self.count])
-                    }
-                }
+                &mut self.entries[idx as usize]
             }
         }
     };
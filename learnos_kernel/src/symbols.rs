@@ -0,0 +1,172 @@
+//! Kernel symbols for turning code addresses into function names.
+//!
+//! The bootloader loads the symbol table of the kernel image along with the kernel, but into
+//! memory that is reclaimed once booting has finished (see `reclaim_boot_memory`). The names of
+//! the functions are therefore copied onto the heap beforehand.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use amd64::{Alignable, PhysAddr, VirtAddr};
+use core::fmt;
+use core::iter;
+use core::mem;
+use core::slice;
+use core::str;
+use kmem::physical::PageFrameRegion;
+use multiboot2::Multiboot2Info;
+use multiboot2::elf::{Demangle, ElfSection, ElfSectionType, ElfSymbol};
+
+use crate::mem::layout::DIRECT_MAPPING;
+
+/// Maximum number of frames that are followed by `backtrace`.
+pub const MAX_BACKTRACE_DEPTH: usize = 32;
+
+/// Frame pointers below this address are considered bogus.
+const KERNEL_HALF_START: usize = 0xFFFF_8000_0000_0000;
+
+/// The function symbols of the kernel, once they have been copied.
+static SYMBOLS: spin::Once<SymbolTable> = spin::Once::new();
+
+/// A function of the kernel image.
+struct Symbol {
+    start: usize,
+    size: usize,
+    /// Range of the name in the name buffer of the symbol table.
+    name_start: usize,
+    name_end: usize,
+}
+
+/// The function symbols of the kernel image, sorted by address.
+struct SymbolTable {
+    symbols: Vec<Symbol>,
+    names: String,
+}
+
+impl SymbolTable {
+    /// Copy the functions from an ELF symbol table and its string table.
+    unsafe fn from_sections(symtab: &ElfSection, strtab: &ElfSection) -> SymbolTable {
+        let symbols: &[ElfSymbol] = slice::from_raw_parts(
+            DIRECT_MAPPING.phys_to_virt(PhysAddr(symtab.address())).as_ptr(),
+            symtab.size() / mem::size_of::<ElfSymbol>());
+        let strings: &[u8] = slice::from_raw_parts(
+            DIRECT_MAPPING.phys_to_virt(PhysAddr(strtab.address())).as_ptr(),
+            strtab.size());
+
+        let mut table = SymbolTable { symbols: Vec::new(), names: String::new() };
+        for symbol in symbols.iter().filter(|symbol| symbol.is_function() && symbol.value() != 0) {
+            let name = match strings.get(symbol.name_offset()..).and_then(|s| s.split(|&b| b == 0).next()) {
+                Some(name) => str::from_utf8(name).unwrap_or("<invalid name>"),
+                None => "<unknown name>",
+            };
+            let name_start = table.names.len();
+            table.names.push_str(name);
+            table.symbols.push(Symbol {
+                start: symbol.value(),
+                size: symbol.size(),
+                name_start: name_start,
+                name_end: table.names.len(),
+            });
+        }
+        table.symbols.sort_unstable_by_key(|symbol| symbol.start);
+        table.names.shrink_to_fit();
+        table.symbols.shrink_to_fit();
+        table
+    }
+
+    /// Return the name of the function containing the address, and the offset into the function.
+    fn lookup(&self, addr: usize) -> Option<(&str, usize)> {
+        let index = match self.symbols.binary_search_by_key(&addr, |symbol| symbol.start) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(next) => next - 1,
+        };
+        let symbol = &self.symbols[index];
+        let offset = addr - symbol.start;
+        if offset == 0 || offset < symbol.size {
+            Some((&self.names[symbol.name_start..symbol.name_end], offset))
+        } else {
+            None
+        }
+    }
+}
+
+/// The symbol table of the kernel and its string table, if the bootloader loaded them.
+fn symbol_sections(mb2: &Multiboot2Info) -> Option<(&'static ElfSection, &'static ElfSection)> {
+    let sections = mb2.elf_sections()?.sections()?;
+    let symtab = sections.iter().find(|section| section.section_type() == ElfSectionType::SYMTAB)?;
+    let strtab = sections.get(symtab.link())?;
+    // allocated sections are not where the section header says they are
+    if symtab.is_allocated() || strtab.is_allocated() || strtab.section_type() != ElfSectionType::STRTAB
+        || symtab.entry_size() != mem::size_of::<ElfSymbol>() {
+        return None;
+    }
+    Some((symtab, strtab))
+}
+
+/// The frames holding the symbol table and its strings during boot, which must be kept until the
+/// symbols have been copied by `init`.
+pub fn boot_regions(mb2: &Multiboot2Info) -> impl Iterator<Item=PageFrameRegion> {
+    symbol_sections(mb2).into_iter()
+        .flat_map(|(symtab, strtab)| iter::once(symtab).chain(iter::once(strtab)))
+        .map(|section| PageFrameRegion::new_including(
+            PhysAddr(section.address()), PhysAddr(section.address() + section.size())))
+}
+
+/// Copy the function symbols of the kernel from the multiboot information.
+/// This requires the heap and must happen before the boot memory is reclaimed.
+pub unsafe fn init(mb2: &Multiboot2Info) {
+    match symbol_sections(mb2) {
+        Some((symtab, strtab)) => {
+            let table = SYMBOLS.call_once(|| SymbolTable::from_sections(symtab, strtab));
+            debug!("[sym] {} function symbols, {} bytes of names", table.symbols.len(), table.names.len());
+        },
+        None => info!("[sym] no kernel symbol table, addresses will not be symbolized"),
+    }
+}
+
+/// Return the name of the kernel function containing the address, and the offset into the function.
+/// The name is still mangled.
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    SYMBOLS.r#try()?.lookup(addr)
+}
+
+/// Call `each` with the return addresses found by following the saved frame pointers, starting
+/// with the caller of this function. The walk stops at the outermost frame, whose frame pointer is
+/// null (see `mem::space`), or at anything that does not look like a mapped frame on the stack.
+#[inline(never)]
+pub fn backtrace<F: FnMut(usize)>(mut each: F) {
+    let mut frame: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(frame) : : : "intel") };
+    for _ in 0..MAX_BACKTRACE_DEPTH {
+        if frame < KERNEL_HALF_START || ! frame.is_aligned(8) || ! is_mapped(frame) || ! is_mapped(frame + 8) {
+            break;
+        }
+        let (saved_frame, return_address) = unsafe { (*(frame as *const usize), *((frame + 8) as *const usize)) };
+        if return_address == 0 {
+            break;
+        }
+        each(return_address);
+        // frames of callers are further up the stack
+        if saved_frame <= frame {
+            break;
+        }
+        frame = saved_frame;
+    }
+}
+
+fn is_mapped(addr: usize) -> bool {
+    unsafe { kmem::paging::walk::translate(VirtAddr(addr)).is_some() }
+}
+
+/// Formats a code address along with the function containing it, like `0x… (learnos_kernel::foo+0x1c)`.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match lookup(self.0) {
+            Some((name, offset)) => write!(f, "{:#x} ({}+{:#x})", self.0, Demangle(name), offset),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Parser for the Multiboot2 ELF-symbols tag, which holds the section headers of the kernel image.
+//!
+//! The bootloader also loads sections that are not part of any segment, like the symbol table.
+//! Their address in the section header is then updated to the physical address they were loaded at,
+//! while the sections of the loaded segments keep their virtual addresses.
+//!
+//! The names in the symbol table are mangled, `Demangle` turns those of Rust functions back into paths.
+
+use core::fmt;
+use core::mem;
+use core::slice;
+
+#[repr(C, packed)]
+pub struct ElfSectionsTag {
+    header: super::Tag,
+    /// Number of section headers.
+    num: u32,
+    /// Size of each section header.
+    entsize: u32,
+    /// Index of the section holding the section names.
+    shndx: u32,
+    /// First byte of the section headers.
+    sections_start: u8,
+}
+
+impl ElfSectionsTag {
+    /// The section headers of the kernel image.
+    /// Returns `None` if the section headers are not those of a 64 bit ELF image.
+    pub fn sections(&self) -> Option<&'static [ElfSection]> {
+        if self.entsize as usize != mem::size_of::<ElfSection>() {
+            return None;
+        }
+        unsafe {
+            Some(slice::from_raw_parts(&self.sections_start as *const u8 as *const ElfSection, self.num as usize))
+        }
+    }
+
+    /// Index of the section holding the section names.
+    pub fn string_table_index(&self) -> usize {
+        self.shndx as usize
+    }
+}
+
+/// A 64 bit ELF section header. The section headers in the tag are not naturally aligned.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct ElfSection {
+    name: u32,
+    section_type: ElfSectionType,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    addralign: u64,
+    entsize: u64,
+}
+
+impl ElfSection {
+    /// Offset of the section name in the section name table.
+    pub fn name_offset(&self) -> usize {
+        self.name as usize
+    }
+
+    pub fn section_type(&self) -> ElfSectionType {
+        self.section_type
+    }
+
+    /// Whether the section occupies memory while the image is executing, i.e. whether its address
+    /// is a virtual one.
+    pub fn is_allocated(&self) -> bool {
+        self.flags & ElfSection::FLAG_ALLOC != 0
+    }
+
+    /// Address of the section, which is physical for sections that are not allocated.
+    pub fn address(&self) -> usize {
+        self.addr as usize
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Index of the associated section, which is the string table for symbol tables.
+    pub fn link(&self) -> usize {
+        self.link as usize
+    }
+
+    /// Size of the entries of sections holding a table, like symbol tables.
+    pub fn entry_size(&self) -> usize {
+        self.entsize as usize
+    }
+
+    const FLAG_ALLOC: u64 = 0x2;
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+#[repr(C)]
+pub struct ElfSectionType(u32);
+
+impl ElfSectionType {
+    pub const NULL: ElfSectionType = ElfSectionType(0);
+    pub const PROGBITS: ElfSectionType = ElfSectionType(1);
+    pub const SYMTAB: ElfSectionType = ElfSectionType(2);
+    pub const STRTAB: ElfSectionType = ElfSectionType(3);
+    pub const NOBITS: ElfSectionType = ElfSectionType(8);
+}
+
+/// An entry of a 64 bit ELF symbol table.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct ElfSymbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+impl ElfSymbol {
+    /// Offset of the symbol name in the associated string table.
+    pub fn name_offset(&self) -> usize {
+        self.name as usize
+    }
+
+    /// Whether the symbol refers to a function.
+    pub fn is_function(&self) -> bool {
+        self.info & 0xF == ElfSymbol::TYPE_FUNC
+    }
+
+    /// Address of the symbol.
+    pub fn value(&self) -> usize {
+        self.value as usize
+    }
+
+    /// Size of the object the symbol refers to, zero if unknown.
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    const TYPE_FUNC: u8 = 2;
+}
+
+/// Formats a symbol name mangled according to the legacy Rust scheme in a readable way, without
+/// the trailing hash. Other names are written as they are.
+pub struct Demangle<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = match self.0.get(3..self.0.len().saturating_sub(1)) {
+            Some(path) if self.0.starts_with("_ZN") && self.0.ends_with('E') => path,
+            _ => return f.write_str(self.0),
+        };
+        if path.is_empty() || path_segments(path).any(|segment| segment.is_none()) {
+            return f.write_str(self.0);
+        }
+        let mut segments = path_segments(path).map(Option::unwrap).peekable();
+        let mut first = true;
+        while let Some(segment) = segments.next() {
+            if segments.peek().is_none() && is_hash(segment) {
+                break;
+            }
+            if ! first {
+                f.write_str("::")?;
+            }
+            write_segment(f, segment)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over the length-prefixed segments of a mangled path, yielding `None` for a malformed one.
+struct PathSegments<'a> {
+    rest: &'a str,
+    failed: bool,
+}
+
+fn path_segments(path: &str) -> PathSegments {
+    PathSegments { rest: path, failed: false }
+}
+
+impl<'a> Iterator for PathSegments<'a> {
+    type Item = Option<&'a str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() || self.failed {
+            return None;
+        }
+        let digits = self.rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+        let segment = self.rest[..digits].parse::<usize>().ok()
+            .and_then(|length| self.rest.get(digits..digits + length))
+            .filter(|segment| ! segment.is_empty());
+        match segment {
+            Some(segment) => self.rest = &self.rest[digits + segment.len()..],
+            None => self.failed = true,
+        }
+        Some(segment)
+    }
+}
+
+/// Whether the segment is the hash that terminates legacy mangled names.
+fn is_hash(segment: &str) -> bool {
+    segment.len() == 17 && segment.starts_with('h') && segment[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Write a path segment, replacing the escape sequences of the mangling scheme.
+fn write_segment(f: &mut fmt::Formatter, segment: &str) -> fmt::Result {
+    let mut rest = if segment.starts_with("_$") { &segment[1..] } else { segment };
+    while ! rest.is_empty() {
+        if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+        } else if rest.starts_with('$') {
+            let end = match rest[1..].find('$') {
+                Some(end) => end + 1,
+                None => return f.write_str(rest),
+            };
+            let unescaped = match &rest[1..end] {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                code if code.starts_with('u') => u32::from_str_radix(&code[1..], 16).ok().and_then(core::char::from_u32),
+                _ => None,
+            };
+            match unescaped {
+                Some(ch) => write!(f, "{}", ch)?,
+                None => f.write_str(&rest[..end + 1])?,
+            }
+            rest = &rest[end + 1..];
+        } else {
+            let end = rest.find(|ch| ch == '$' || ch == '.').unwrap_or(rest.len()).max(1);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The bytes of a section header.
+    fn section_bytes(section_type: u32, flags: u64, addr: u64, size: u64, link: u32, entsize: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut push = |value: u64, size: usize| bytes.extend((0..size).map(|i| (value >> (8 * i)) as u8));
+        push(7, 4);
+        push(section_type as u64, 4);
+        push(flags, 8);
+        push(addr, 8);
+        push(0, 8);
+        push(size, 8);
+        push(link as u64, 4);
+        push(0, 4);
+        push(8, 8);
+        push(entsize, 8);
+        bytes
+    }
+
+    #[test]
+    fn test_elf_sections() {
+        let mut bytes = vec![9, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 64, 0, 0, 0, 2, 0, 0, 0];
+        bytes.extend(section_bytes(0, 0, 0, 0, 0, 0));
+        bytes.extend(section_bytes(1, 0x6, 0xFFFF_FFFF_8010_0000, 0x5000, 0, 0));
+        bytes.extend(section_bytes(2, 0, 0x0020_0000, 0x1800, 2, 24));
+        let size = bytes.len() as u8;
+        bytes[4] = size;
+
+        let tag = unsafe { &*(bytes.as_ptr() as *const ElfSectionsTag) };
+        assert_eq!(tag.string_table_index(), 2);
+        let sections = tag.sections().unwrap();
+        assert_eq!(sections.len(), 3);
+        assert_eq!(sections[0].section_type(), ElfSectionType::NULL);
+        assert_eq!(sections[1].section_type(), ElfSectionType::PROGBITS);
+        assert!(sections[1].is_allocated());
+        assert_eq!(sections[1].address(), 0xFFFF_FFFF_8010_0000);
+        assert_eq!(sections[2].section_type(), ElfSectionType::SYMTAB);
+        assert!(! sections[2].is_allocated());
+        assert_eq!((sections[2].address(), sections[2].size()), (0x0020_0000, 0x1800));
+        assert_eq!((sections[2].link(), sections[2].entry_size()), (2, 24));
+        assert_eq!(sections[2].name_offset(), 7);
+
+        // section headers of a 32 bit image are smaller
+        bytes[12] = 40;
+        let tag = unsafe { &*(bytes.as_ptr() as *const ElfSectionsTag) };
+        assert!(tag.sections().is_none());
+    }
+
+    #[test]
+    fn test_elf_symbol() {
+        assert_eq!(mem::size_of::<ElfSymbol>(), 24);
+        let symbol = ElfSymbol { name: 1, info: 0x12, other: 0, shndx: 1, value: 0xFFFF_FFFF_8010_0040, size: 0x80 };
+        assert!(symbol.is_function());
+        assert_eq!((symbol.value(), symbol.size()), (0xFFFF_FFFF_8010_0040, 0x80));
+        let object = ElfSymbol { info: 0x11, ..symbol };
+        assert!(! object.is_function());
+    }
+
+    fn demangle(name: &str) -> String {
+        format!("{}", Demangle(name))
+    }
+
+    #[test]
+    fn test_demangle() {
+        assert_eq!(demangle("_ZN14learnos_kernel4main17h0123456789abcdefE"), "learnos_kernel::main");
+        assert_eq!(demangle("_ZN4core3ptr13drop_in_placeE"), "core::ptr::drop_in_place");
+        assert_eq!(demangle("_ZN65_$LT$learnos_kernel..vga..Console$u20$as$u20$core..fmt..Write$GT$9write_str17h0123456789abcdefE"),
+            "<learnos_kernel::vga::Console as core::fmt::Write>::write_str");
+        assert_eq!(demangle("_ZN4kmem4slab27$u7b$$u7b$closure$u7d$$u7d$17hfedcba9876543210E"), "kmem::slab::{{closure}}");
+        // a hash is only dropped at the end
+        assert_eq!(demangle("_ZN17h0123456789abcdef3fooE"), "h0123456789abcdef::foo");
+        // unknown escapes are kept
+        assert_eq!(demangle("_ZN7$XX$$u$3fooE"), "$XX$$u$::foo");
+    }
+
+    #[test]
+    fn test_demangle_passthrough() {
+        for name in ["memcpy", "_ZN", "_ZNE", "_ZN3fooE1", "_ZN14learnos_kernel4ma", "_ZN3foo0E", "_ZN99fooE", "_ZNxE"].iter() {
+            assert_eq!(demangle(name), *name);
+        }
+    }
+}
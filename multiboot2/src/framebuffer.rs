@@ -0,0 +1,180 @@
+//! Parser for the Multiboot2 framebuffer info tag.
+
+use amd64::PhysAddr;
+
+use core::mem;
+use core::slice;
+
+#[repr(C, packed)]
+pub struct FramebufferTag {
+    header: super::Tag,
+    address: u64,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    bpp: u8,
+    framebuffer_type: u8,
+    reserved: u16,
+    /// First byte of the color information, whose layout depends on the framebuffer type.
+    color_info_start: u8,
+}
+
+impl FramebufferTag {
+    /// Physical address of the framebuffer.
+    pub fn address(&self) -> PhysAddr {
+        PhysAddr(self.address as usize)
+    }
+
+    /// Number of bytes per line.
+    pub fn pitch(&self) -> usize {
+        self.pitch as usize
+    }
+
+    /// Width in pixels, or characters for EGA text mode.
+    pub fn width(&self) -> usize {
+        self.width as usize
+    }
+
+    /// Height in pixels, or characters for EGA text mode.
+    pub fn height(&self) -> usize {
+        self.height as usize
+    }
+
+    pub fn bits_per_pixel(&self) -> u8 {
+        self.bpp
+    }
+
+    /// Number of bytes occupied by the framebuffer.
+    pub fn size_in_bytes(&self) -> usize {
+        self.pitch() * self.height()
+    }
+
+    /// How the pixels are encoded.
+    /// The palette is clamped and truncated RGB color information is reported as `Unknown`,
+    /// so that nothing beyond the end of the tag is read.
+    pub fn format<'a>(&'a self) -> FramebufferFormat<'a> {
+        let color_info = self.color_info();
+        match self.framebuffer_type {
+            0 if color_info.len() >= 2 => {
+                let count = color_info[0] as usize | (color_info[1] as usize) << 8;
+                let count = count.min((color_info.len() - 2) / mem::size_of::<PaletteEntry>());
+                let palette = color_info[2..].as_ptr() as *const PaletteEntry;
+                FramebufferFormat::Indexed(unsafe { slice::from_raw_parts(palette, count) })
+            },
+            1 if color_info.len() >= 6 => {
+                let field = |index: usize| ColorField {
+                    position: color_info[2 * index],
+                    size: color_info[2 * index + 1],
+                };
+                FramebufferFormat::Rgb { red: field(0), green: field(1), blue: field(2) }
+            },
+            2 => FramebufferFormat::EgaText,
+            other => FramebufferFormat::Unknown(other),
+        }
+    }
+
+    /// The color information following the fixed part of the tag, as far as it is covered by the tag size.
+    fn color_info(&self) -> &[u8] {
+        let offset = mem::size_of::<FramebufferTag>() - 1;
+        let length = self.header.size().saturating_sub(offset);
+        unsafe { slice::from_raw_parts(&self.color_info_start as *const u8, length) }
+    }
+}
+
+/// The pixel format of a framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferFormat<'a> {
+    /// Each pixel is an index into the palette.
+    Indexed(&'a [PaletteEntry]),
+    /// Each pixel consists of the three color components at the given bits.
+    Rgb { red: ColorField, green: ColorField, blue: ColorField },
+    /// The framebuffer is a VGA text buffer.
+    EgaText,
+    /// An unknown framebuffer type, or a known one whose color information is missing.
+    Unknown(u8),
+}
+
+/// The bits of a pixel holding one color component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorField {
+    /// Index of the least significant bit.
+    pub position: u8,
+    /// Number of bits.
+    pub size: u8,
+}
+
+/// A color of the palette of an indexed framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct PaletteEntry {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The bytes of a framebuffer tag for a 640x480 framebuffer with the given type and color information.
+    fn tag_bytes(bpp: u8, framebuffer_type: u8, color_info: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut push = |value: u64, size: usize| bytes.extend((0..size).map(|i| (value >> (8 * i)) as u8));
+        push(8, 4);
+        push(32 + color_info.len() as u64, 4);
+        push(0xFD00_0000, 8);
+        push(640 * bpp as u64 / 8, 4);
+        push(640, 4);
+        push(480, 4);
+        bytes.extend_from_slice(&[bpp, framebuffer_type, 0, 0]);
+        bytes.extend_from_slice(color_info);
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> &FramebufferTag {
+        unsafe { &*(bytes.as_ptr() as *const FramebufferTag) }
+    }
+
+    #[test]
+    fn test_framebuffer_rgb() {
+        let bytes = tag_bytes(32, 1, &[16, 8, 8, 8, 0, 8]);
+        let tag = parse(&bytes);
+        assert_eq!(tag.address(), PhysAddr(0xFD00_0000));
+        assert_eq!((tag.width(), tag.height(), tag.pitch()), (640, 480, 2560));
+        assert_eq!(tag.bits_per_pixel(), 32);
+        assert_eq!(tag.size_in_bytes(), 2560 * 480);
+        assert_eq!(tag.format(), FramebufferFormat::Rgb {
+            red: ColorField { position: 16, size: 8 },
+            green: ColorField { position: 8, size: 8 },
+            blue: ColorField { position: 0, size: 8 },
+        });
+    }
+
+    #[test]
+    fn test_framebuffer_indexed() {
+        let bytes = tag_bytes(8, 0, &[2, 0, 0, 0, 0, 0xFF, 0x80, 0x00]);
+        let tag = parse(&bytes);
+        match tag.format() {
+            FramebufferFormat::Indexed(palette) => assert_eq!(palette, &[
+                PaletteEntry { red: 0, green: 0, blue: 0 },
+                PaletteEntry { red: 0xFF, green: 0x80, blue: 0 },
+            ]),
+            other => panic!("unexpected format {:?}", other),
+        }
+
+        assert_eq!(parse(&tag_bytes(16, 2, &[])).format(), FramebufferFormat::EgaText);
+    }
+
+    #[test]
+    fn test_framebuffer_truncated() {
+        // the palette claims 16 entries, but the tag only holds one and a half
+        let bytes = tag_bytes(8, 0, &[16, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(parse(&bytes).format(), FramebufferFormat::Indexed(&[PaletteEntry { red: 1, green: 2, blue: 3 }]));
+
+        let bytes = tag_bytes(8, 0, &[]);
+        assert_eq!(parse(&bytes).format(), FramebufferFormat::Unknown(0));
+
+        let bytes = tag_bytes(32, 1, &[16, 8, 8, 8]);
+        assert_eq!(parse(&bytes).format(), FramebufferFormat::Unknown(1));
+    }
+}
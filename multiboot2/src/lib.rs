@@ -18,6 +18,8 @@ use core::iter::{Iterator, FusedIterator};
 use core::str;
 use core::slice;
 
+pub mod elf;
+pub mod framebuffer;
 pub mod memmap;
 
 /// Root of Multiboot2 info data.
@@ -69,6 +71,20 @@ impl Multiboot2Info {
             .map(|t| unsafe { &*(t as *const BootLoaderTag) } )
             .map(|t| t.name() )
     }
+
+    pub fn elf_sections(&self) -> Option<&'static elf::ElfSectionsTag> {
+        self.tags()
+            .find(|t| t.tag_type() == TagType::ELF_SECTIONS)
+            .map(|t| (t as *const Tag) )
+            .map(|t| unsafe { &*(t as *const elf::ElfSectionsTag) } )
+    }
+
+    pub fn framebuffer(&self) -> Option<&'static framebuffer::FramebufferTag> {
+        self.tags()
+            .find(|t| t.tag_type() == TagType::FRAMEBUFFER)
+            .map(|t| (t as *const Tag) )
+            .map(|t| unsafe { &*(t as *const framebuffer::FramebufferTag) } )
+    }
 }
 
 #[repr(C, packed)]
@@ -102,6 +118,8 @@ impl TagType {
     const BOOT_LOADER_NAME: TagType = TagType(2);
     const MODULE: TagType = TagType(3);
     const MEMORY_MAP: TagType = TagType(6);
+    const FRAMEBUFFER: TagType = TagType(8);
+    const ELF_SECTIONS: TagType = TagType(9);
 }
 
 /// An iterator over the tags in the multiboot structure.
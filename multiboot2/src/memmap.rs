@@ -1,6 +1,6 @@
 //! Parser for the Multiboot2 memory map.
 
-use amd64::{PhysAddr};
+use amd64::{Alignable, PhysAddr};
 
 use core::iter::{Iterator, FusedIterator};
 use core::fmt;
@@ -24,6 +24,11 @@ impl MemoryMapTag {
             }
         }
     }
+
+    /// Return a sanitized version of the memory map.
+    pub fn sanitized(&self) -> SanitizedMemoryMap {
+        SanitizedMemoryMap::new(self.regions())
+    }
 }
 
 /// An iterator over the entries of a multiboot2 memory map.
@@ -58,6 +63,7 @@ pub struct EntryType(u32);
 
 impl EntryType {
     pub const AVAILABLE: EntryType = EntryType(1);
+    pub const RESERVED: EntryType = EntryType(2);
     pub const AVAILABLE_ACPI: EntryType = EntryType(3);
     pub const RESERVED_HIBERNATION: EntryType = EntryType(4);
     pub const DEFECTIVE: EntryType = EntryType(5);
@@ -76,6 +82,20 @@ impl fmt::Debug for EntryType {
     }
 }
 
+impl EntryType {
+    /// How restrictive the use of memory of this type is.
+    /// When regions overlap, the type with the highest restriction wins.
+    fn restriction(self) -> u32 {
+        match self {
+            EntryType::AVAILABLE => 0,
+            EntryType::AVAILABLE_ACPI => 1,
+            EntryType::RESERVED_HIBERNATION => 2,
+            EntryType::DEFECTIVE => 4,
+            _ => 3,
+        }
+    }
+}
+
 #[repr(C, packed)]
 pub struct Region {
     base_addr: PhysAddr,
@@ -85,6 +105,15 @@ pub struct Region {
 }
 
 impl Region {
+    pub fn new(base_addr: PhysAddr, length: usize, entry_type: EntryType) -> Region {
+        Region {
+            base_addr: base_addr,
+            length: length as u64,
+            entry_type: entry_type,
+            reserved: 0,
+        }
+    }
+
     /// Return whether the memory range described by this entry is available to the OS.
     pub fn is_available(&self) -> bool {
         let type_ = self.entry_type;
@@ -106,4 +135,175 @@ impl Region {
     pub fn entry_type(&self) -> EntryType {
         self.entry_type
     }
-}
\ No newline at end of file
+}
+
+/// Maximum number of regions in a sanitized memory map.
+pub const SANITIZED_MAX_REGIONS: usize = 64;
+
+/// Granularity to which sanitized regions are aligned.
+const PAGE_SIZE: usize = 4096;
+
+/// An entry of a sanitized memory map.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SanitizedRegion {
+    base_addr: PhysAddr,
+    end_addr: PhysAddr,
+    entry_type: EntryType,
+}
+
+impl SanitizedRegion {
+    /// Return whether the memory range described by this entry is available to the OS.
+    pub fn is_available(&self) -> bool {
+        self.entry_type == EntryType::AVAILABLE
+    }
+
+    pub fn base_addr(&self) -> PhysAddr {
+        self.base_addr
+    }
+
+    pub fn end_addr(&self) -> PhysAddr {
+        self.end_addr
+    }
+
+    pub fn length(&self) -> usize {
+        self.end_addr.0 - self.base_addr.0
+    }
+
+    pub fn entry_type(&self) -> EntryType {
+        self.entry_type
+    }
+}
+
+/// A memory map that is sorted by address, without overlapping or empty entries,
+/// where adjacent entries of the same type are merged and all entries are page aligned.
+///
+/// Where entries of the original map overlap, the most restrictive type wins.
+/// Available memory is shrunk to whole pages, while all other entries are grown to whole pages.
+pub struct SanitizedMemoryMap {
+    regions: [SanitizedRegion; SANITIZED_MAX_REGIONS],
+    count: usize,
+}
+
+impl SanitizedMemoryMap {
+    /// Sanitize the entries of a memory map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the sanitized map has more than `SANITIZED_MAX_REGIONS` entries.
+    pub fn new<'a, I: Iterator<Item=&'a Region> + Clone>(regions: I) -> SanitizedMemoryMap {
+        let mut map = SanitizedMemoryMap {
+            regions: [SanitizedRegion { base_addr: PhysAddr(0), end_addr: PhysAddr(0), entry_type: EntryType::RESERVED }; SANITIZED_MAX_REGIONS],
+            count: 0,
+        };
+
+        let aligned = regions
+            .map(|r| {
+                let (base, end) = if r.is_available() {
+                    (r.base_addr().align_up(PAGE_SIZE), r.end_addr().align_down(PAGE_SIZE))
+                } else {
+                    (r.base_addr().align_down(PAGE_SIZE), r.end_addr().align_up(PAGE_SIZE))
+                };
+                (base, end, r.entry_type())
+            })
+            .filter(|&(base, end, _)| base < end);
+
+        // Sweep over all region boundaries in ascending order. Between two consecutive
+        // boundaries, the set of overlapping regions does not change.
+        let mut pos = match aligned.clone().map(|(base, _, _)| base).min() {
+            Some(pos) => pos,
+            None => return map,
+        };
+        loop {
+            let next = aligned.clone()
+                .flat_map(|(base, end, _)| Some(base).into_iter().chain(Some(end).into_iter()))
+                .filter(|&boundary| boundary > pos)
+                .min();
+            let next = match next {
+                Some(next) => next,
+                None => break,
+            };
+            let entry_type = aligned.clone()
+                .filter(|&(base, end, _)| base <= pos && pos < end)
+                .map(|(_, _, entry_type)| entry_type)
+                .max_by_key(|entry_type| entry_type.restriction());
+            if let Some(entry_type) = entry_type {
+                map.push(SanitizedRegion { base_addr: pos, end_addr: next, entry_type: entry_type });
+            }
+            pos = next;
+        }
+        map
+    }
+
+    /// The entries of the sanitized map in ascending order.
+    pub fn regions(&self) -> &[SanitizedRegion] {
+        &self.regions[0..self.count]
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a SanitizedRegion> + Clone {
+        self.regions().iter()
+    }
+
+    fn push(&mut self, region: SanitizedRegion) {
+        if self.count > 0 {
+            let last = &mut self.regions[self.count - 1];
+            if last.end_addr == region.base_addr && last.entry_type == region.entry_type {
+                last.end_addr = region.end_addr;
+                return;
+            }
+        }
+        assert!(self.count < SANITIZED_MAX_REGIONS, "too many memory map entries");
+        self.regions[self.count] = region;
+        self.count += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sanitized(regions: &[Region]) -> Vec<(usize, usize, EntryType)> {
+        SanitizedMemoryMap::new(regions.iter()).iter()
+            .map(|r| (r.base_addr().0, r.end_addr().0, r.entry_type()))
+            .collect()
+    }
+
+    #[test]
+    fn test_sanitize_sorts_and_merges() {
+        let map = [
+            Region::new(PhysAddr(0x10_0000), 0x10_0000, EntryType::AVAILABLE),
+            Region::new(PhysAddr(0x0), 0x9_F000, EntryType::AVAILABLE),
+            Region::new(PhysAddr(0x5000), 0, EntryType::RESERVED),
+            Region::new(PhysAddr(0x20_0000), 0x10_0000, EntryType::AVAILABLE),
+            Region::new(PhysAddr(0xF_0000), 0x1_0000, EntryType::RESERVED),
+        ];
+        assert_eq!(sanitized(&map), vec![
+            (0x0, 0x9_F000, EntryType::AVAILABLE),
+            (0xF_0000, 0x10_0000, EntryType::RESERVED),
+            (0x10_0000, 0x30_0000, EntryType::AVAILABLE),
+        ]);
+    }
+
+    #[test]
+    fn test_sanitize_overlaps_and_alignment() {
+        let map = [
+            Region::new(PhysAddr(0x0), 0x10_0000, EntryType::AVAILABLE),
+            // unaligned reserved region grows to whole pages
+            Region::new(PhysAddr(0x1800), 0x1000, EntryType::RESERVED),
+            // defective memory wins over everything else
+            Region::new(PhysAddr(0x8000), 0x1000, EntryType::DEFECTIVE),
+            Region::new(PhysAddr(0x7000), 0x3000, EntryType::AVAILABLE_ACPI),
+            // unaligned available region shrinks to whole pages
+            Region::new(PhysAddr(0x20_0800), 0x2000, EntryType::AVAILABLE),
+        ];
+        assert_eq!(sanitized(&map), vec![
+            (0x0, 0x1000, EntryType::AVAILABLE),
+            (0x1000, 0x3000, EntryType::RESERVED),
+            (0x3000, 0x7000, EntryType::AVAILABLE),
+            (0x7000, 0x8000, EntryType::AVAILABLE_ACPI),
+            (0x8000, 0x9000, EntryType::DEFECTIVE),
+            (0x9000, 0xA000, EntryType::AVAILABLE_ACPI),
+            (0xA000, 0x10_0000, EntryType::AVAILABLE),
+            (0x20_1000, 0x20_2000, EntryType::AVAILABLE),
+        ]);
+    }
+}